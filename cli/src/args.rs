@@ -20,6 +20,30 @@ pub struct QArgs {
 	#[arg(long)]
 	pub proxy: Option<String>,
 
+	// the following are for dohc only
+	#[arg(long, default_value = "/dns-query")]
+	pub path: String,
+
+	#[arg(long, default_value = "POST")]
+	pub method: String,
+
+	// "Name: value", can be repeated
+	#[arg(long)]
+	pub header: Vec<String>,
+
+	// try HTTP/3 first, fallback to HTTP/2
+	#[arg(long)]
+	pub http3: bool,
+
+	#[arg(long)]
+	pub bind_address: Option<std::net::IpAddr>,
+
+	#[arg(long)]
+	pub interface: Option<String>,
+
+	#[arg(long)]
+	pub fwmark: Option<u32>,
+
 	#[arg(long, default_value_t = 1)]
 	pub repeat: usize,
 
@@ -43,6 +67,6 @@ pub struct QArgs {
 
 #[derive(Subcommand)]
 pub enum Cmd {
-	Query(QArgs),
+	Query(Box<QArgs>),
 	Proxy { listen: String, origin: String },
 }
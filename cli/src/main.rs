@@ -11,7 +11,12 @@ use hickory_proto::{
 use log::*;
 use tokio::{net::UdpSocket, select, signal::ctrl_c, time::sleep};
 
-use diverge::{conf, dohc::Dohc, resolver};
+use diverge::{
+	bind::BindOpts,
+	conf,
+	dohc::{Dohc, DohcOpts},
+	resolver,
+};
 
 mod args;
 use args::*;
@@ -25,9 +30,9 @@ async fn main() {
 	match args.cmd {
 		Cmd::Query(args) => {
 			if &args.proto == "dohc" {
-				dohc_query(args).await;
+				dohc_query(*args).await;
 			} else {
-				query(args).await;
+				query(*args).await;
 			}
 		}
 		Cmd::Proxy { listen, origin } => {
@@ -92,14 +97,12 @@ async fn proxy(listen: &str, origin: &str) {
 
 async fn query(args: QArgs) {
 	let r = resolver::from(&conf::UpstreamSec {
-		name: "".to_string(),
 		addrs: vec![args.server.parse().unwrap()],
 		protocol: conf::parse_proto(&args.proto),
 		port: args.port,
 		tls_dns_name: args.tls_dns_name.clone(),
-		disable_aaaa: false,
-		ips: vec![],
-		domains: vec![],
+		bind: bind_opts(&args),
+		..conf::UpstreamSec::new("")
 	});
 
 	let mut intv = args.interval;
@@ -127,12 +130,36 @@ async fn query(args: QArgs) {
 	}
 }
 
+fn bind_opts(args: &QArgs) -> BindOpts {
+	BindOpts {
+		address: args.bind_address,
+		interface: args.interface.clone(),
+		fwmark: args.fwmark,
+	}
+}
+
 async fn dohc_query(args: QArgs) {
+	let bind = bind_opts(&args);
 	let dohc = Dohc::new(
 		args.tls_dns_name.unwrap_or(args.server.clone()),
 		vec![args.server.parse().unwrap()],
 		args.port,
-		args.proxy,
+		&DohcOpts {
+			proxy: args.proxy,
+			path: args.path,
+			http3: args.http3,
+			method: args.method.to_ascii_uppercase().parse().unwrap(),
+			headers: args
+				.header
+				.iter()
+				.map(|h| {
+					let (k, v) = h.split_once(':').expect("expected \"Name: value\"");
+					(k.trim_ascii().to_string(), v.trim_ascii().to_string())
+				})
+				.collect(),
+			..Default::default()
+		},
+		&bind,
 	);
 
 	let mut h = Header::new();
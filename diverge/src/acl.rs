@@ -0,0 +1,117 @@
+// client access control, allow/deny CIDR lists on the client address
+//	the most specific prefix wins
+//	with an allow list, clients not on it are denied
+
+use std::{net::IpAddr, str::FromStr};
+
+use crate::ip_map::IpMap;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum DenyAction {
+	Refuse,
+	// no response at all, for UDP and TCP, DoH gets 403
+	Drop,
+}
+
+impl FromStr for DenyAction {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"refuse" => Ok(DenyAction::Refuse),
+			"drop" => Ok(DenyAction::Drop),
+			_ => Err(format!("invalid deny action: {}", s)),
+		}
+	}
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+	Allow,
+	Refuse,
+	// only by the rate limit, for UDP
+	Truncate,
+	Drop,
+}
+
+pub struct Acl {
+	map: IpMap<bool>,
+	action: DenyAction,
+}
+
+impl Acl {
+	pub fn new(allow: &[(IpAddr, u32)], deny: &[(IpAddr, u32)], action: DenyAction) -> Self {
+		let mut map = IpMap::new(allow.is_empty());
+		for &(addr, len) in allow {
+			map.insert(addr, len, true);
+		}
+		for &(addr, len) in deny {
+			map.insert(addr, len, false);
+		}
+		Self { map, action }
+	}
+
+	pub fn check(&self, client: IpAddr) -> Access {
+		if self.map.get(client.to_canonical()) {
+			return Access::Allow;
+		}
+		match self.action {
+			DenyAction::Refuse => Access::Refuse,
+			DenyAction::Drop => Access::Drop,
+		}
+	}
+}
+
+// "192.0.2.0/24", or a bare address for a single host
+pub fn parse_cidr(s: &str) -> Result<(IpAddr, u32), String> {
+	let (addr, len) = match s.split_once('/') {
+		Some((a, l)) => (a, Some(l)),
+		None => (s, None),
+	};
+	let addr: IpAddr = addr.parse().map_err(|_| format!("invalid CIDR: {}", s))?;
+	let max = if addr.is_ipv4() { 32 } else { 128 };
+	let len = match len {
+		Some(l) => l.parse().map_err(|_| format!("invalid CIDR: {}", s))?,
+		None => max,
+	};
+	if len > max {
+		return Err(format!("invalid CIDR: {}", s));
+	}
+	Ok((addr, len))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+		let parse = |l: &[&str]| -> Vec<_> { l.iter().map(|s| parse_cidr(s).unwrap()).collect() };
+		Acl::new(&parse(allow), &parse(deny), DenyAction::Refuse)
+	}
+
+	#[test]
+	fn test() {
+		let check = |acl: &Acl, ip: &str| acl.check(ip.parse().unwrap()) == Access::Allow;
+
+		let a = acl(&[], &[]);
+		assert!(check(&a, "192.0.2.1"));
+
+		let a = acl(&[], &["192.0.2.0/24"]);
+		assert!(!check(&a, "192.0.2.1"));
+		assert!(check(&a, "198.51.100.1"));
+
+		let a = acl(&["10.0.0.0/8", "::1"], &["10.0.5.0/24"]);
+		assert!(check(&a, "10.0.0.1"));
+		assert!(!check(&a, "10.0.5.1"));
+		assert!(!check(&a, "192.0.2.1"));
+		assert!(check(&a, "::1"));
+		assert!(!check(&a, "::2"));
+		assert!(check(&a, "::ffff:10.0.0.1"));
+
+		assert!(parse_cidr("10.0.0.0/33").is_err());
+		assert!(parse_cidr("10.0.0/8").is_err());
+		assert_eq!(parse_cidr("2001:db8::/32").unwrap().1, 32);
+	}
+}
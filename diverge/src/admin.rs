@@ -0,0 +1,97 @@
+// admin API, over HTTP on localhost or a unix socket
+//	GET  /explain?name=www.example.com&type=A
+//	GET  /explain?ip=1.1.1.1
+//		both take client=ip:port, for the view, default 127.0.0.1:0
+//	GET  /upstreams
+//	GET  /metrics
+//	POST /reload, with Content-Type: application/json, against CSRF from browsers
+
+use std::{net::SocketAddr, rc::Rc, str::FromStr};
+
+use hickory_proto::rr::{Name, RecordType};
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Request, Response, StatusCode};
+use log::*;
+
+use crate::{
+	diverge::Diverge,
+	httpd::{self, Body, Listen},
+};
+
+pub async fn serve(listen: Listen, diverge: Rc<Diverge>) {
+	info!("serving admin API on {}", listen);
+	httpd::serve(listen, move |req| handle(diverge.clone(), req)).await
+}
+
+async fn handle<B>(diverge: Rc<Diverge>, req: Request<B>) -> Response<Body> {
+	let params: Vec<(String, String)> = req
+		.uri()
+		.query()
+		.map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+		.unwrap_or_default();
+	let param = |k: &str| {
+		params
+			.iter()
+			.find_map(|(pk, v)| if pk == k { Some(v.as_str()) } else { None })
+	};
+
+	match (req.method(), req.uri().path()) {
+		(&Method::GET, "/explain") => {
+			// views depend on it
+			let client = match param("client").map(SocketAddr::from_str) {
+				None => SocketAddr::from(([127, 0, 0, 1], 0)),
+				Some(Ok(c)) => c,
+				Some(Err(e)) => return bad_request(&format!("invalid client: {}", e)),
+			};
+			if let Some(ip) = param("ip") {
+				return match ip.parse() {
+					Ok(ip) => httpd::json(&diverge.explain_ip(ip, client)),
+					Err(_) => bad_request(&format!("invalid ip: {}", ip)),
+				};
+			}
+			let Some(name) = param("name") else {
+				return bad_request("expected name or ip");
+			};
+			let name = match Name::from_utf8(name) {
+				Ok(mut name) => {
+					name.set_fqdn(true);
+					name
+				}
+				Err(e) => return bad_request(&format!("invalid name {}: {}", name, e)),
+			};
+			let rtype = match RecordType::from_str(param("type").unwrap_or("A")) {
+				Ok(t) => t,
+				Err(e) => return bad_request(&format!("invalid type: {}", e)),
+			};
+			info!("admin: explain {} {}", name, rtype);
+			httpd::json(&diverge.explain_name(&name, rtype, client).await)
+		}
+		(&Method::GET, "/upstreams") => httpd::json(&diverge.upstreams_json()),
+		(&Method::GET, "/metrics") => Response::builder()
+			.header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+			.body(Full::new(Bytes::from(diverge.metrics().render())))
+			.unwrap(),
+		(&Method::POST, "/reload") => {
+			// not a content type a form can send
+			let json = req
+				.headers()
+				.get(header::CONTENT_TYPE)
+				.and_then(|v| v.to_str().ok())
+				.is_some_and(|v| v.starts_with("application/json"));
+			if !json {
+				return httpd::status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+			}
+			info!("admin: reload");
+			diverge.reload();
+			httpd::status(StatusCode::OK)
+		}
+		_ => httpd::status(StatusCode::NOT_FOUND),
+	}
+}
+
+fn bad_request(msg: &str) -> Response<Body> {
+	Response::builder()
+		.status(StatusCode::BAD_REQUEST)
+		.body(Full::new(Bytes::from(msg.to_string())))
+		.unwrap()
+}
@@ -0,0 +1,176 @@
+// per upstream socket binding, so queries leave through the matching link
+//	without extra routing policy rules for the DNS server addresses
+// and listening sockets
+
+use std::{
+	future::Future,
+	io,
+	net::{IpAddr, SocketAddr},
+	pin::Pin,
+};
+
+use hickory_proto::{iocompat::AsyncIoTokioAsStd, TokioTime};
+use hickory_resolver::name_server::{RuntimeProvider, TokioHandle};
+use log::*;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+use crate::systemd;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Default)]
+pub struct BindOpts {
+	pub address: Option<IpAddr>,
+	// SO_BINDTODEVICE
+	pub interface: Option<String>,
+	// SO_MARK
+	pub fwmark: Option<u32>,
+}
+
+impl BindOpts {
+	fn socket(&self, domain: Domain, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+		let s = Socket::new(domain, ty, Some(protocol))?;
+		s.set_nonblocking(true)?;
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		{
+			if let Some(interface) = self.interface.as_ref() {
+				s.bind_device(Some(interface.as_bytes()))?;
+			}
+			if let Some(mark) = self.fwmark {
+				s.set_mark(mark)?;
+			}
+		}
+		#[cfg(not(any(target_os = "linux", target_os = "android")))]
+		if self.interface.is_some() || self.fwmark.is_some() {
+			return Err(io::Error::new(
+				io::ErrorKind::Unsupported,
+				"interface and fwmark are only supported on linux",
+			));
+		}
+		Ok(s)
+	}
+
+	pub fn bind_udp(&self, local_addr: SocketAddr) -> io::Result<UdpSocket> {
+		// keep the port, hickory randomizes it
+		let local_addr = match self.address {
+			Some(a) => SocketAddr::new(a, local_addr.port()),
+			None => local_addr,
+		};
+		let s = self.socket(Domain::for_address(local_addr), Type::DGRAM, Protocol::UDP)?;
+		s.bind(&local_addr.into())?;
+		UdpSocket::from_std(s.into())
+	}
+
+	pub async fn connect_tcp(&self, server_addr: SocketAddr) -> io::Result<TcpStream> {
+		let s = self.socket(
+			Domain::for_address(server_addr),
+			Type::STREAM,
+			Protocol::TCP,
+		)?;
+		if let Some(a) = self.address {
+			s.bind(&SocketAddr::new(a, 0).into())?;
+		}
+		TcpSocket::from_std_stream(s.into())
+			.connect(server_addr)
+			.await
+	}
+}
+
+// v6 listeners are v6 only, so [::]:53 and 0.0.0.0:53 can be listened on together
+const UDP_RECV_BUFFER: usize = 4 << 20;
+
+fn listen_socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+	let s = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+	if addr.is_ipv6() {
+		s.set_only_v6(true)?;
+	}
+	s.set_nonblocking(true)?;
+	Ok(s)
+}
+
+pub fn listen_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+	if let Some(s) = systemd::take(&addr.into(), Type::DGRAM) {
+		return UdpSocket::from_std(s.into());
+	}
+	let s = listen_socket(addr, Type::DGRAM, Protocol::UDP)?;
+	// absorbs bursts, capped by net.core.rmem_max
+	if let Err(e) = s.set_recv_buffer_size(UDP_RECV_BUFFER) {
+		warn!("udp {} set receive buffer error: {}", addr, e);
+	}
+	s.bind(&addr.into())?;
+	UdpSocket::from_std(s.into())
+}
+
+pub fn listen_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+	if let Some(s) = systemd::take(&addr.into(), Type::STREAM) {
+		return TcpListener::from_std(s.into());
+	}
+	let s = listen_socket(addr, Type::STREAM, Protocol::TCP)?;
+	s.set_reuse_address(true)?;
+	s.bind(&addr.into())?;
+	s.listen(1024)?;
+	TcpListener::from_std(s.into())
+}
+
+// TokioRuntimeProvider, with sockets created according to BindOpts
+#[derive(Clone)]
+pub struct BindProvider {
+	handle: TokioHandle,
+	opts: BindOpts,
+}
+
+impl BindProvider {
+	pub fn new(opts: BindOpts) -> Self {
+		Self {
+			handle: TokioHandle::default(),
+			opts,
+		}
+	}
+}
+
+impl RuntimeProvider for BindProvider {
+	type Handle = TokioHandle;
+	type Timer = TokioTime;
+	type Udp = UdpSocket;
+	type Tcp = AsyncIoTokioAsStd<TcpStream>;
+
+	fn create_handle(&self) -> Self::Handle {
+		self.handle.clone()
+	}
+
+	fn connect_tcp(
+		&self,
+		server_addr: SocketAddr,
+	) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Tcp>>>> {
+		let opts = self.opts.clone();
+		Box::pin(async move { opts.connect_tcp(server_addr).await.map(AsyncIoTokioAsStd) })
+	}
+
+	fn bind_udp(
+		&self,
+		local_addr: SocketAddr,
+		_server_addr: SocketAddr,
+	) -> Pin<Box<dyn Send + Future<Output = io::Result<Self::Udp>>>> {
+		let r = self.opts.bind_udp(local_addr);
+		Box::pin(async move { r })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_bind_address() {
+		let opts = BindOpts {
+			address: Some("127.0.0.1".parse().unwrap()),
+			..Default::default()
+		};
+		let s = opts.bind_udp("0.0.0.0:0".parse().unwrap()).unwrap();
+		assert_eq!(s.local_addr().unwrap().ip(), opts.address.unwrap());
+
+		let l = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let s = opts.connect_tcp(l.local_addr().unwrap()).await.unwrap();
+		assert_eq!(s.local_addr().unwrap().ip(), opts.address.unwrap());
+	}
+}
@@ -12,7 +12,18 @@ use std::path::Path;
 
 use log::warn;
 
-use crate::utils::read_lines;
+use crate::{
+	acl::{parse_cidr, DenyAction},
+	bind::BindOpts,
+	dohc::DohcOpts,
+	ecs::Ecs,
+	httpd::Listen,
+	pool::Strategy,
+	querylog::parse_size,
+	ratelimit::LimitAction,
+	stamp::Stamp,
+	utils::read_lines,
+};
 
 // this is the part that's generic
 
@@ -58,14 +69,21 @@ pub trait Conf: Sized {
 
 // the following is specific to diverge's conf
 
-use std::net::{IpAddr, SocketAddr};
+use std::{
+	fmt::{self, Display},
+	net::{IpAddr, SocketAddr},
+	str::FromStr,
+	time::Duration,
+};
 
 use hickory_resolver::config::Protocol;
+use reqwest::Method;
 
 #[cfg_attr(debug_assertions, derive(Debug))]
 pub struct DivergeConf {
 	pub global: GlobalSec,
 	pub upstreams: Vec<UpstreamSec>,
+	pub views: Vec<ViewSec>,
 }
 
 impl Conf for DivergeConf {
@@ -73,11 +91,16 @@ impl Conf for DivergeConf {
 		Self {
 			global: GlobalSec::new(),
 			upstreams: Vec::new(),
+			views: Vec::new(),
 		}
 	}
 	fn sec_mut(&mut self, name: &str) -> &mut dyn Section {
 		if name.to_ascii_lowercase().as_str() == "global" {
 			&mut self.global
+		} else if let Some(view) = name.strip_prefix("view:") {
+			self.views.push(ViewSec::new(view.trim_ascii()));
+			let len = self.views.len();
+			&mut self.views[len - 1]
 		} else {
 			self.upstreams.push(UpstreamSec::new(name));
 			let len = self.upstreams.len();
@@ -88,13 +111,139 @@ impl Conf for DivergeConf {
 
 #[cfg_attr(debug_assertions, derive(Debug))]
 pub struct GlobalSec {
-	pub listen: SocketAddr,
+	pub listen: Vec<Listener>,
+	// for dot:// and doh://
+	pub tls_cert: Option<String>,
+	pub tls_key: Option<String>,
+	// client CIDRs
+	pub allow: Vec<(IpAddr, u32)>,
+	pub deny: Vec<(IpAddr, u32)>,
+	pub deny_action: DenyAction,
+	// per client prefix, 0 disables
+	pub rate_limit: f64,
+	// defaults to rate_limit, at least 1
+	pub rate_limit_burst: Option<f64>,
+	pub rate_limit_prefix_v4: u8,
+	pub rate_limit_prefix_v6: u8,
+	pub rate_limit_action: LimitAction,
+	pub metrics: Option<Listen>,
+	pub admin: Option<Listen>,
+	pub query_log: Option<String>,
+	pub query_log_size: u64,
+	pub query_log_keep: usize,
+	pub circuit_threshold: u32,
+	pub probe_interval: Duration,
+	pub probe_name: String,
+	pub down_policy: DownPolicy,
+	pub cname_policy: CnamePolicy,
+	// consecutive ip map decisions for a name before it's learned
+	pub learn_threshold: u32,
+	// names learned an hour at most
+	pub learn_limit: u32,
+	// upstream to resolve hostname addresses through
+	pub bootstrap: Option<String>,
+	pub bootstrap_interval: Duration,
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, PartialEq)]
+pub enum Listener {
+	// no prefix or udp://
+	Udp(SocketAddr),
+	// tcp://
+	Tcp(SocketAddr),
+	// dot://, requires tls_cert and tls_key
+	Dot(SocketAddr),
+	// doh://, over TLS if tls_cert and tls_key are set, otherwise plain HTTP
+	//	could be a unix socket, doh://unix:/path/to/socket
+	Doh(Listen),
+}
+
+impl FromStr for Listener {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (proto, addr) = s.split_once("://").unwrap_or(("udp", s));
+		let sock = || {
+			addr.parse::<SocketAddr>()
+				.map_err(|e| format!("invalid listen address {}: {}", addr, e))
+		};
+		match proto.to_ascii_lowercase().as_str() {
+			"udp" => Ok(Listener::Udp(sock()?)),
+			"tcp" => Ok(Listener::Tcp(sock()?)),
+			"dot" => Ok(Listener::Dot(sock()?)),
+			"doh" => addr
+				.parse()
+				.map(Listener::Doh)
+				.map_err(|e| format!("invalid listen address {}: {}", addr, e)),
+			_ => Err(format!("unsupported listen protocol: {}", proto)),
+		}
+	}
+}
+
+impl Display for Listener {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Listener::Udp(addr) => write!(f, "udp://{}", addr),
+			Listener::Tcp(addr) => write!(f, "tcp://{}", addr),
+			Listener::Dot(addr) => write!(f, "dot://{}", addr),
+			Listener::Doh(listen) => write!(f, "doh://{}", listen),
+		}
+	}
+}
+
+// what to do when the domain map chooses an upstream that is down
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum DownPolicy {
+	// query it anyway
+	Query,
+	// treat it as a domain map miss, this leaks the query to other upstreams
+	Fallback,
+	ServFail,
+}
+
+// what to do when a CNAME target in an answer is mapped to another upstream
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum CnamePolicy {
+	// resolve the target through that upstream
+	Resolve,
+	// answer with nothing
+	Discard,
+	// keep the answer, like the CNAME isn't there
+	Ignore,
 }
 
 impl GlobalSec {
-	fn new() -> Self {
+	#[allow(clippy::new_without_default)]
+	pub fn new() -> Self {
 		Self {
-			listen: SocketAddr::from(([127, 0, 0, 1], 1054)),
+			listen: vec![Listener::Udp(SocketAddr::from(([127, 0, 0, 1], 1054)))],
+			tls_cert: None,
+			tls_key: None,
+			allow: Vec::new(),
+			deny: Vec::new(),
+			deny_action: DenyAction::Refuse,
+			rate_limit: 0.,
+			rate_limit_burst: None,
+			rate_limit_prefix_v4: 32,
+			rate_limit_prefix_v6: 64,
+			rate_limit_action: LimitAction::Refuse,
+			metrics: None,
+			admin: None,
+			query_log: None,
+			query_log_size: 16 << 20,
+			query_log_keep: 3,
+			circuit_threshold: 3,
+			probe_interval: Duration::from_secs(5),
+			probe_name: ".".to_string(),
+			down_policy: DownPolicy::Query,
+			cname_policy: CnamePolicy::Resolve,
+			learn_threshold: 3,
+			learn_limit: 10,
+			bootstrap: None,
+			bootstrap_interval: Duration::from_secs(3600),
 		}
 	}
 }
@@ -102,35 +251,180 @@ impl GlobalSec {
 impl Section for GlobalSec {
 	fn set(&mut self, k: &str, v: &str) {
 		match k.to_ascii_lowercase().as_str() {
-			"listen" => self.listen = v.parse().unwrap(),
+			"listen" => {
+				self.listen = v
+					.split_ascii_whitespace()
+					.map(|l| l.parse().unwrap_or_else(|e| panic!("{}", e)))
+					.collect();
+				if self.listen.is_empty() {
+					panic!("listen requires at least one address");
+				}
+			}
+			"tls_cert" => self.tls_cert = Some(v.to_string()),
+			"tls_key" => self.tls_key = Some(v.to_string()),
+			"allow" => self.allow.extend(parse_cidrs(v)),
+			"deny" => self.deny.extend(parse_cidrs(v)),
+			"deny_action" => self.deny_action = v.parse().unwrap_or_else(|e| panic!("{}", e)),
+			"rate_limit" => self.rate_limit = v.parse().unwrap(),
+			"rate_limit_burst" => match v.parse() {
+				// a query takes a whole token
+				Ok(v) if v >= 1. => self.rate_limit_burst = Some(v),
+				_ => panic!("invalid rate_limit_burst, expected at least 1: {}", v),
+			},
+			"rate_limit_prefix_v4" => match v.parse() {
+				Ok(v @ 0..=32) => self.rate_limit_prefix_v4 = v,
+				_ => panic!("invalid rate_limit_prefix_v4: {}", v),
+			},
+			"rate_limit_prefix_v6" => match v.parse() {
+				Ok(v @ 0..=128) => self.rate_limit_prefix_v6 = v,
+				_ => panic!("invalid rate_limit_prefix_v6: {}", v),
+			},
+			"rate_limit_action" => {
+				self.rate_limit_action = v.parse().unwrap_or_else(|e| panic!("{}", e))
+			}
+			"metrics" => self.metrics = Some(v.parse().unwrap()),
+			"admin" => {
+				// no auth, keep it local
+				let l = v.parse().unwrap();
+				if let Listen::Tcp(a) = &l {
+					if !a.ip().is_loopback() {
+						panic!("admin must be on localhost or a unix socket: {}", v);
+					}
+				}
+				self.admin = Some(l);
+			}
+			"query_log" => self.query_log = Some(v.to_string()),
+			"query_log_size" => match parse_size(v) {
+				Ok(v) => self.query_log_size = v,
+				Err(e) => panic!("invalid size {}: {}", v, e),
+			},
+			"query_log_keep" => self.query_log_keep = v.parse().unwrap(),
+			"circuit_threshold" => self.circuit_threshold = v.parse().unwrap(),
+			"probe_interval" => self.probe_interval = Duration::from_secs_f32(v.parse().unwrap()),
+			"probe_name" => self.probe_name = v.to_string(),
+			"down_policy" => {
+				self.down_policy = match v.to_ascii_lowercase().as_str() {
+					"query" => DownPolicy::Query,
+					"fallback" => DownPolicy::Fallback,
+					"servfail" => DownPolicy::ServFail,
+					_ => panic!("invalid down_policy: {}", v),
+				}
+			}
+			"cname_policy" => {
+				self.cname_policy = match v.to_ascii_lowercase().as_str() {
+					"resolve" => CnamePolicy::Resolve,
+					"discard" => CnamePolicy::Discard,
+					"ignore" => CnamePolicy::Ignore,
+					_ => panic!("invalid cname_policy: {}", v),
+				}
+			}
+			"learn_threshold" => self.learn_threshold = v.parse().unwrap(),
+			"learn_limit" => self.learn_limit = v.parse().unwrap(),
+			"bootstrap" => self.bootstrap = Some(v.to_string()),
+			"bootstrap_interval" => {
+				self.bootstrap_interval = Duration::from_secs_f32(v.parse().unwrap())
+			}
 			_ => warn!("unknown key: {}", k),
 		}
 	}
 }
 
+// [view:name], a policy for clients matching any of the CIDRs
+//	the most specific match among all views wins, other clients get the default
 #[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone)]
+pub struct ViewSec {
+	pub name: String,
+	pub clients: Vec<(IpAddr, u32)>,
+	// upstream names in order, empty for all of them in config order
+	pub upstreams: Vec<String>,
+	// (upstream name, files), if none, the upstreams' own lists apply
+	pub domains: Vec<(String, Vec<String>)>,
+	pub ips: Vec<(String, Vec<String>)>,
+}
+
+impl ViewSec {
+	pub fn new(name: &str) -> Self {
+		Self {
+			name: name.to_string(),
+			clients: Vec::new(),
+			upstreams: Vec::new(),
+			domains: Vec::new(),
+			ips: Vec::new(),
+		}
+	}
+}
+
+impl Section for ViewSec {
+	fn set(&mut self, k: &str, v: &str) {
+		// "upstream file file ..."
+		let lists = |v: &str| {
+			let mut it = v.split_ascii_whitespace().map(|s| s.to_string());
+			match (it.next(), it.collect::<Vec<_>>()) {
+				(Some(name), files) if !files.is_empty() => (name, files),
+				_ => panic!("invalid {}, expected an upstream name then files: {}", k, v),
+			}
+		};
+		match k.to_ascii_lowercase().as_str() {
+			"clients" => self.clients.extend(parse_cidrs(v)),
+			"upstreams" => {
+				self.upstreams = v.split_ascii_whitespace().map(|s| s.to_string()).collect()
+			}
+			"domains" => self.domains.push(lists(v)),
+			"ips" => self.ips.push(lists(v)),
+			_ => warn!("unknown key: {}", k),
+		}
+	}
+}
+
+fn parse_cidrs(v: &str) -> impl Iterator<Item = (IpAddr, u32)> + '_ {
+	v.split_ascii_whitespace()
+		.map(|c| parse_cidr(c).unwrap_or_else(|e| panic!("{}", e)))
+}
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone)]
 pub struct UpstreamSec {
 	pub name: String,
 	pub protocol: Protocol,
 	pub addrs: Vec<IpAddr>,
+	// addresses that are not IPs, resolved via the bootstrap upstream
+	pub hosts: Vec<String>,
+	// None leaves it to hickory
+	pub strategy: Option<Strategy>,
 	pub port: Option<u16>,
 	pub tls_dns_name: Option<String>,
 	pub ips: Vec<String>,
 	pub domains: Vec<String>,
+	// generated domain list, learned from ip map decisions
+	pub learn: Option<String>,
 	pub disable_aaaa: bool,
+	// EDNS Client Subnet policy
+	pub ecs: Ecs,
+	pub bind: BindOpts,
+	// DoH via Dohc(reqwest) instead of hickory, protocol = doh-reqwest
+	pub dohc: bool,
+	pub dohc_opts: DohcOpts,
 }
 
 impl UpstreamSec {
-	fn new(name: &str) -> Self {
+	pub fn new(name: &str) -> Self {
 		Self {
 			name: name.to_string(),
 			protocol: Protocol::Udp,
 			addrs: Vec::new(),
+			hosts: Vec::new(),
+			strategy: None,
 			port: None,
 			tls_dns_name: None,
 			ips: Vec::new(),
 			domains: Vec::new(),
+			learn: None,
 			disable_aaaa: false,
+			ecs: Ecs::Strip,
+			bind: BindOpts::default(),
+			dohc: false,
+			dohc_opts: DohcOpts::default(),
 		}
 	}
 }
@@ -139,21 +433,47 @@ impl Section for UpstreamSec {
 	fn set(&mut self, k: &str, v: &str) {
 		match k.to_ascii_lowercase().as_str() {
 			"addresses" => {
-				self.addrs = v
-					.split(' ')
-					.filter_map(|e| {
-						let e = e.trim_ascii();
-						if e.is_empty() {
-							return None;
-						}
-						match e.parse() {
-							Ok(e) => Some(e),
-							Err(e) => panic!("invalid address: {}", e),
-						}
-					})
-					.collect();
+				self.addrs.clear();
+				self.hosts.clear();
+				for e in v.split_ascii_whitespace() {
+					match e.parse() {
+						Ok(a) => self.addrs.push(a),
+						Err(_) if is_hostname(e) => self.hosts.push(e.to_string()),
+						Err(_) => panic!("invalid address: {}", e),
+					}
+				}
+			}
+			"protocol" => {
+				self.dohc = v.eq_ignore_ascii_case("doh-reqwest");
+				self.protocol = if self.dohc {
+					Protocol::Https
+				} else {
+					parse_proto(v)
+				};
+			}
+			"stamp" => {
+				let s: Stamp = match v.parse() {
+					Ok(s) => s,
+					Err(e) => panic!("invalid stamp {}: {}", v, e),
+				};
+				self.dohc = false;
+				self.protocol = s.protocol;
+				self.addrs = s.addr.into_iter().collect();
+				// no address, resolve the hostname via bootstrap
+				self.hosts = match s.addr {
+					Some(_) => Vec::new(),
+					None => s.hostname.clone().into_iter().collect(),
+				};
+				self.port = s.port;
+				self.tls_dns_name = s.hostname;
+				if let Some(path) = s.path {
+					self.dohc_opts.path = path;
+				}
 			}
-			"protocol" => self.protocol = parse_proto(v),
+			"strategy" => match v.parse() {
+				Ok(v) => self.strategy = Some(v),
+				Err(e) => panic!("{}", e),
+			},
 			"port" => match v.parse() {
 				Ok(v) => self.port = Some(v),
 				Err(e) => panic!("invalid port {}: {}", v, e),
@@ -161,12 +481,71 @@ impl Section for UpstreamSec {
 			"tls_dns_name" => self.tls_dns_name = Some(v.to_string()),
 			"ips" => self.ips = v.split_ascii_whitespace().map(|s| s.to_string()).collect(),
 			"domains" => self.domains = v.split_ascii_whitespace().map(|s| s.to_string()).collect(),
+			"learn" => self.learn = Some(v.to_string()),
 			"disable_aaaa" => self.disable_aaaa = v.parse().unwrap(),
+			"ecs" => match v.parse() {
+				Ok(v) => self.ecs = v,
+				Err(e) => panic!("{}", e),
+			},
+			"bind_address" => match v.parse() {
+				Ok(v) => self.bind.address = Some(v),
+				Err(e) => panic!("invalid bind_address {}: {}", v, e),
+			},
+			"interface" => self.bind.interface = Some(v.to_string()),
+			"fwmark" => {
+				// decimal or 0x hex, like ip rule
+				let m = match v.strip_prefix("0x") {
+					Some(h) => u32::from_str_radix(h, 16),
+					None => v.parse(),
+				};
+				match m {
+					Ok(m) => self.bind.fwmark = Some(m),
+					Err(e) => panic!("invalid fwmark {}: {}", v, e),
+				}
+			}
+			"path" => {
+				if !v.starts_with('/') {
+					panic!("invalid path, expected a leading /: {}", v);
+				}
+				self.dohc_opts.path = v.to_string();
+			}
+			"method" => {
+				self.dohc_opts.method = match v.to_ascii_lowercase().as_str() {
+					"get" => Method::GET,
+					"post" => Method::POST,
+					_ => panic!("invalid method: {}", v),
+				}
+			}
+			// "Name: value", repeat the key for more headers
+			"header" => match v.split_once(':') {
+				Some((k, v)) => self
+					.dohc_opts
+					.headers
+					.push((k.trim_ascii().to_string(), v.trim_ascii().to_string())),
+				None => panic!("invalid header, expected \"Name: value\": {}", v),
+			},
+			"http3" => self.dohc_opts.http3 = v.parse().unwrap(),
+			"proxy" => self.dohc_opts.proxy = Some(v.to_string()),
+			"pool_size" => self.dohc_opts.pool_size = v.parse().unwrap(),
+			"pool_idle_timeout" => {
+				self.dohc_opts.pool_idle_timeout = Duration::from_secs_f32(v.parse().unwrap())
+			}
+			"keepalive" => self.dohc_opts.keepalive = Duration::from_secs_f32(v.parse().unwrap()),
+			"keepalive_timeout" => {
+				self.dohc_opts.keepalive_timeout = Duration::from_secs_f32(v.parse().unwrap())
+			}
 			_ => warn!("unknown key: \"{}\"", k),
 		}
 	}
 }
 
+fn is_hostname(s: &str) -> bool {
+	s.contains('.')
+		&& s.split('.')
+			.all(|l| !l.is_empty() && l.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-'))
+		&& !s.bytes().all(|c| c.is_ascii_digit() || c == b'.')
+}
+
 pub fn parse_proto(proto: &str) -> Protocol {
 	match proto.to_ascii_lowercase().as_str() {
 		"udp" => Protocol::Udp,
@@ -174,6 +553,7 @@ pub fn parse_proto(proto: &str) -> Protocol {
 		"tls" => Protocol::Tls,
 		"https" => Protocol::Https,
 		"h3" => Protocol::H3,
+		"quic" => Protocol::Quic,
 		_ => panic!("unsupported protocol: {}", proto),
 	}
 }
@@ -192,4 +572,84 @@ mod tests {
 		let dc = DivergeConf::from_file("../example.conf").unwrap();
 		println!("{:?}", dc);
 	}
+
+	#[test]
+	fn test_listen() {
+		let mut g = GlobalSec::new();
+		g.set(
+			"listen",
+			"127.0.0.1:53 udp://[::1]:53 tcp://127.0.0.1:53 dot://0.0.0.0:853 doh://unix:/run/doh.sock",
+		);
+		assert_eq!(
+			g.listen,
+			vec![
+				Listener::Udp("127.0.0.1:53".parse().unwrap()),
+				Listener::Udp("[::1]:53".parse().unwrap()),
+				Listener::Tcp("127.0.0.1:53".parse().unwrap()),
+				Listener::Dot("0.0.0.0:853".parse().unwrap()),
+				Listener::Doh(Listen::Unix("/run/doh.sock".into())),
+			]
+		);
+		assert!("quic://127.0.0.1:853".parse::<Listener>().is_err());
+	}
+
+	#[test]
+	fn test_addresses() {
+		let mut u = UpstreamSec::new("X");
+		u.set("addresses", "1.1.1.1 dns.example.net  2606:4700::1111");
+		assert_eq!(
+			u.addrs,
+			vec![
+				"1.1.1.1".parse::<IpAddr>().unwrap(),
+				"2606:4700::1111".parse().unwrap()
+			]
+		);
+		assert_eq!(u.hosts, vec!["dns.example.net"]);
+	}
+
+	#[test]
+	fn test_stamp() {
+		let mut u = UpstreamSec::new("X");
+		u.set("stamp", "sdns://BAEAAAAAAAAAAAATZG5zLmFkZ3VhcmQtZG5zLmNvbQ");
+		assert_eq!(u.protocol, Protocol::Quic);
+		assert!(u.addrs.is_empty());
+		assert_eq!(u.hosts, vec!["dns.adguard-dns.com"]);
+		assert_eq!(u.tls_dns_name.as_deref(), Some("dns.adguard-dns.com"));
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_invalid_address() {
+		UpstreamSec::new("X").set("addresses", "1.1.1.300");
+	}
+
+	#[test]
+	fn test_view() {
+		let mut v = ViewSec::new("guest");
+		v.set("clients", "192.168.100.0/24");
+		v.set("clients", "fd00::/8");
+		v.set("upstreams", "X");
+		v.set("domains", "X a.lst b.lst");
+		assert_eq!(v.clients.len(), 2);
+		assert_eq!(v.upstreams, ["X"]);
+		assert_eq!(v.domains[0].1, ["a.lst", "b.lst"]);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_rate_limit_burst() {
+		GlobalSec::new().set("rate_limit_burst", "0.5");
+	}
+
+	#[test]
+	#[should_panic(expected = "listen")]
+	fn test_listen_empty() {
+		GlobalSec::new().set("listen", "");
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_admin_not_local() {
+		GlobalSec::new().set("admin", "0.0.0.0:9155");
+	}
 }
@@ -1,67 +1,390 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+	cell::{Cell, RefCell},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	ptr,
+	rc::Rc,
+};
 
 use futures::{stream::FuturesUnordered, StreamExt};
 use hickory_proto::{
-	op::{header::MessageType, Header, Message, Query, ResponseCode},
-	rr::{DNSClass, Name, Record, RecordType},
+	op::{header::MessageType, Edns, Header, Message, Query, ResponseCode},
+	rr::{
+		rdata::{
+			svcb::{IpHint, SvcParamValue, SVCB},
+			HTTPS,
+		},
+		DNSClass, Name, RData, Record, RecordType,
+	},
 };
-use hickory_resolver::{error::ResolveError, TokioAsyncResolver};
+use hickory_resolver::error::ResolveError;
 use log::*;
-use tokio::time::{timeout, Duration};
+use serde_json::{json, Value};
+use tokio::{
+	sync::Notify,
+	time::{interval, timeout, Duration, Instant, MissedTickBehavior},
+};
 
-use crate::{conf::DivergeConf, domain_map::DomainMap, ip_map::IpMap, resolver, utils::FromLst};
+use crate::{
+	acl::{Access, Acl},
+	conf::{CnamePolicy, DivergeConf, DownPolicy, UpstreamSec, ViewSec},
+	domain_map::DomainMap,
+	ecs::{Ecs, Subnet},
+	ip_map::IpMap,
+	learn::Learn,
+	metrics::{Decision, Metrics},
+	querylog::{QueryLog, Trace},
+	ratelimit::{LimitAction, RateLimit},
+	resolver::{self, Resolver},
+	utils::FromLst,
+};
 
 const UPSTREAM_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+// longer CNAME chains in an answer are not followed
+const MAX_CNAME_CHAIN: usize = 8;
 
 struct Upstream {
 	name: String,
-	resolver: TokioAsyncResolver,
+	// swapped when hostname addresses resolve differently
+	resolver: RefCell<Rc<Resolver>>,
+	addrs: RefCell<Vec<IpAddr>>,
 	disable_aaaa: bool,
+	conf: UpstreamSec,
+	last_success: Cell<Option<Instant>>,
+	last_failure: Cell<Option<Instant>>,
+	// consecutive errors and timeouts
+	failures: Cell<u32>,
+	// circuit opened
+	down: Cell<bool>,
 }
 
-pub struct Diverge {
+impl Upstream {
+	fn resolver(&self) -> Rc<Resolver> {
+		self.resolver.borrow().clone()
+	}
+}
+
+// domain/ip lists, swapped as a whole on reload
+//	values are upstream indices
+struct Lists {
 	domain_map: DomainMap<u8>,
 	ip_map: IpMap<u8>,
+}
+
+impl Lists {
+	// the last upstream takes the rest of the ip map
+	fn load(last: usize, domains: &[(usize, String)], ips: &[(usize, String)]) -> Self {
+		let mut domain_map = DomainMap::new();
+		let mut ip_map = IpMap::new(last as u8);
+		for (i, fname) in domains {
+			domain_map.append_from_file(fname, *i as u8);
+		}
+		for (i, fname) in ips {
+			ip_map.append_from_file(fname, *i as u8);
+		}
+		Self { domain_map, ip_map }
+	}
+}
+
+// the upstreams and lists that apply to a set of clients
+struct View {
+	name: String,
+	// upstream indices, in the order of precedence
+	order: Vec<usize>,
+	// (upstream index, file)
+	domains: Vec<(usize, String)>,
+	ips: Vec<(usize, String)>,
+	lists: RefCell<Rc<Lists>>,
+}
+
+impl View {
+	fn new(
+		name: &str,
+		order: Vec<usize>,
+		domains: Vec<(usize, String)>,
+		ips: Vec<(usize, String)>,
+	) -> Self {
+		let lists = Lists::load(*order.last().unwrap(), &domains, &ips);
+		Self {
+			name: name.to_string(),
+			order,
+			domains,
+			ips,
+			lists: RefCell::new(Rc::new(lists)),
+		}
+	}
+
+	// all upstreams, with their own lists
+	fn default(upstreams: &[Upstream]) -> Self {
+		let files = |f: fn(&UpstreamSec) -> &Vec<String>| {
+			upstreams
+				.iter()
+				.enumerate()
+				.flat_map(|(i, u)| f(&u.conf).iter().map(move |fname| (i, fname.clone())))
+				.collect()
+		};
+		Self::new(
+			"default",
+			(0..upstreams.len()).collect(),
+			files(|c| &c.domains),
+			files(|c| &c.ips),
+		)
+	}
+
+	fn from(conf: &ViewSec, upstreams: &[Upstream]) -> Self {
+		let index = |name: &str| match upstreams.iter().position(|u| u.name == name) {
+			Some(i) => i,
+			None => panic!("view {}: unknown upstream {}", conf.name, name),
+		};
+		let order: Vec<_> = if conf.upstreams.is_empty() {
+			(0..upstreams.len()).collect()
+		} else {
+			conf.upstreams.iter().map(|n| index(n)).collect()
+		};
+		let files = |lists: &[(String, Vec<String>)], own: fn(&UpstreamSec) -> &Vec<String>| {
+			let mut v = Vec::new();
+			for (name, files) in lists {
+				let i = index(name);
+				if !order.contains(&i) {
+					panic!("view {}: upstream {} is not in the view", conf.name, name);
+				}
+				v.extend(files.iter().map(|f| (i, f.clone())));
+			}
+			if lists.is_empty() {
+				for &i in order.iter() {
+					v.extend(own(&upstreams[i].conf).iter().map(|f| (i, f.clone())));
+				}
+			}
+			v
+		};
+		let domains = files(&conf.domains, |c| &c.domains);
+		let ips = files(&conf.ips, |c| &c.ips);
+		info!("view {} configured", conf.name);
+		Self::new(&conf.name, order, domains, ips)
+	}
+
+	fn lists(&self) -> Rc<Lists> {
+		self.lists.borrow().clone()
+	}
+
+	fn reload(&self) {
+		let lists = Lists::load(*self.order.last().unwrap(), &self.domains, &self.ips);
+		*self.lists.borrow_mut() = Rc::new(lists);
+	}
+}
+
+pub struct Diverge {
+	acl: Acl,
+	rate_limit: Option<RateLimit>,
+	// the default view is the last
+	views: Vec<View>,
+	// client to view index
+	view_map: IpMap<u8>,
 	upstreams: Vec<Upstream>,
+	metrics: Metrics,
+	query_log: Option<QueryLog>,
+	learn: Option<Learn>,
+	circuit_threshold: u32,
+	probe_interval: Duration,
+	probe_name: String,
+	down_policy: DownPolicy,
+	cname_policy: CnamePolicy,
+	bootstrap: Option<usize>,
+	bootstrap_interval: Duration,
+	stopping: Cell<bool>,
+	stop: Notify,
 }
 
 impl Diverge {
 	pub fn from(conf: &DivergeConf) -> Self {
-		let mut domain_map = DomainMap::new();
-		let mut ip_map = IpMap::new((conf.upstreams.len() - 1) as u8);
 		let upstreams: Vec<_> = conf
 			.upstreams
 			.iter()
-			.enumerate()
-			.map(|(i, upconf)| {
-				for fname in upconf.domains.iter() {
-					domain_map.append_from_file(fname, i as u8);
-				}
-				for fname in upconf.ips.iter() {
-					ip_map.append_from_file(fname, i as u8);
-				}
+			.map(|upconf| {
 				info!("upstream {} configured", &upconf.name);
+				let mut conf = upconf.clone();
+				// loaded like any other list
+				conf.domains.extend(upconf.learn.clone());
 				Upstream {
 					name: upconf.name.clone(),
-					resolver: resolver::from(upconf),
+					resolver: RefCell::new(Rc::new(resolver::from(upconf))),
+					addrs: RefCell::new(upconf.addrs.clone()),
 					disable_aaaa: upconf.disable_aaaa,
+					conf,
+					last_success: Cell::new(None),
+					last_failure: Cell::new(None),
+					failures: Cell::new(0),
+					down: Cell::new(false),
 				}
 			})
 			.collect();
+		let bootstrap = match conf.global.bootstrap.as_ref() {
+			Some(name) => match upstreams.iter().position(|u| &u.name == name) {
+				Some(i) if upstreams[i].conf.hosts.is_empty() => Some(i),
+				Some(_) => panic!("bootstrap upstream {} has hostname addresses", name),
+				None => panic!("unknown bootstrap upstream: {}", name),
+			},
+			None => {
+				if let Some(u) = upstreams.iter().find(|u| !u.conf.hosts.is_empty()) {
+					panic!(
+						"upstream {} has hostname addresses but no bootstrap",
+						u.name
+					);
+				}
+				None
+			}
+		};
+		// before the lists are loaded, this creates the files
+		let g = &conf.global;
+		let learn = conf.upstreams.iter().any(|u| u.learn.is_some()).then(|| {
+			Learn::new(
+				conf.upstreams.iter().map(|u| u.learn.clone()).collect(),
+				g.learn_threshold,
+				g.learn_limit,
+			)
+		});
+		let mut views: Vec<_> = conf
+			.views
+			.iter()
+			.map(|v| View::from(v, &upstreams))
+			.collect();
+		views.push(View::default(&upstreams));
+		let mut view_map = IpMap::new((views.len() - 1) as u8);
+		for (i, v) in conf.views.iter().enumerate() {
+			for &(addr, len) in v.clients.iter() {
+				view_map.insert(addr, len, i as u8);
+			}
+		}
+		let metrics = Metrics::new(upstreams.iter().map(|u| u.name.as_str()));
+		let query_log = conf.global.query_log.as_ref().map(|path| {
+			QueryLog::new(
+				path,
+				conf.global.query_log_size,
+				conf.global.query_log_keep,
+				upstreams.iter().map(|u| u.name.clone()).collect(),
+			)
+		});
+		let acl = Acl::new(
+			&conf.global.allow,
+			&conf.global.deny,
+			conf.global.deny_action,
+		);
+		let rate_limit = (g.rate_limit > 0.).then(|| {
+			RateLimit::new(
+				g.rate_limit,
+				// a query takes a whole token
+				g.rate_limit_burst.unwrap_or(g.rate_limit.max(1.)),
+				g.rate_limit_prefix_v4,
+				g.rate_limit_prefix_v6,
+				g.rate_limit_action,
+			)
+		});
 		Self {
-			domain_map,
-			ip_map,
+			acl,
+			rate_limit,
+			views,
+			view_map,
 			upstreams,
+			metrics,
+			query_log,
+			learn,
+			circuit_threshold: conf.global.circuit_threshold,
+			probe_interval: conf.global.probe_interval,
+			probe_name: conf.global.probe_name.clone(),
+			down_policy: conf.global.down_policy,
+			cname_policy: conf.global.cname_policy,
+			bootstrap,
+			bootstrap_interval: conf.global.bootstrap_interval,
+			stopping: Cell::new(false),
+			stop: Notify::new(),
+		}
+	}
+
+	fn view(&self, client: IpAddr) -> &View {
+		&self.views[self.view_map.get(client.to_canonical()) as usize]
+	}
+
+	// re-read domain/ip lists, queries in flight keep using the old ones
+	pub fn reload(&self) {
+		info!("reloading lists");
+		for view in self.views.iter() {
+			view.reload();
+		}
+	}
+
+	pub fn metrics(&self) -> &Metrics {
+		&self.metrics
+	}
+
+	// tell listeners to stop accepting, and finish the queries they have
+	pub fn shutdown(&self) {
+		self.stopping.set(true);
+		self.stop.notify_waiters();
+	}
+
+	// resolves once shutdown is called
+	pub async fn stopping(&self) {
+		let stop = self.stop.notified();
+		if self.stopping.get() {
+			return;
 		}
+		stop.await
 	}
 
-	pub async fn query(&self, q: Vec<u8>) -> Option<Vec<u8>> {
+	pub fn flush(&self) {
+		if let Some(query_log) = self.query_log.as_ref() {
+			query_log.flush();
+		}
+	}
+
+	// ACL and rate limit, listeners check this for each query before query()
+	//	truncation only makes sense over UDP
+	pub fn admit(&self, client: IpAddr, udp: bool) -> Access {
+		let access = self.acl.check(client);
+		if access != Access::Allow {
+			trace!("query from {} denied by acl", client);
+			self.metrics.denied("acl");
+			return access;
+		}
+		if let Some(rate_limit) = self.rate_limit.as_ref() {
+			if !rate_limit.check(client) {
+				trace!("query from {} rate limited", client);
+				self.metrics.denied("rate_limit");
+				return match rate_limit.action {
+					LimitAction::Truncate if udp => Access::Truncate,
+					_ => Access::Refuse,
+				};
+			}
+		}
+		Access::Allow
+	}
+
+	// the response to a query not admitted, None to drop it
+	pub fn deny(&self, q: &[u8], access: Access) -> Option<Vec<u8>> {
+		let query = Message::from_vec(q).ok()?;
+		let mut header = Header::response_from_request(query.header());
+		match access {
+			Access::Refuse => {
+				header.set_response_code(ResponseCode::Refused);
+			}
+			Access::Truncate => {
+				header.set_truncated(true);
+			}
+			Access::Allow | Access::Drop => return None,
+		}
+		// not query logged, this could be a flood
+		self.metrics.response(header.response_code());
+		mk_msg(header, query.queries().first(), None, None)
+	}
+
+	pub async fn query(&self, q: Vec<u8>, client: SocketAddr) -> Option<Vec<u8>> {
 		// seriously, why not just let user send it as is and let the resolver do the work?
 		let query = Message::from_vec(&q)
 			.map_err(|e| error!("invalid dns message: {}", e))
 			.ok()?;
 		trace!("dns query: {}", query);
 		let query_header = query.header();
+		let trace = Trace::new(client, Subnet::from_message(&query));
+		let view = self.view(client.ip());
 
 		let mut header = Header::response_from_request(query_header);
 		let mut answers = None;
@@ -69,26 +392,28 @@ impl Diverge {
 		if query_header.message_type() != MessageType::Query {
 			debug!("expected query, got {}", query_header.message_type());
 			header.set_response_code(ResponseCode::FormErr);
-			return mk_msg(header, None, answers);
+			return self.mk_msg(&trace, header, None, answers);
 		}
 		// we only support 1 question
 		if query_header.query_count() == 0 {
 			debug!("expected 1 question, got {}", query_header.query_count());
 			header.set_response_code(ResponseCode::FormErr);
-			return mk_msg(header, None, answers);
+			return self.mk_msg(&trace, header, None, answers);
 		}
 
 		let q = &query.queries()[0];
+		self.metrics.query(q.query_class(), q.query_type());
+		trace.question(q.name().to_ascii(), q.query_type());
 
 		if query_header.query_count() > 1 {
 			debug!("expected 1 question, got {}", query_header.query_count());
 			header.set_response_code(ResponseCode::NotImp);
-			return mk_msg(header, Some(q), answers);
+			return self.mk_msg(&trace, header, Some(q), answers);
 		}
 		if query_header.answer_count() != 0 {
 			debug!("expected 0 answer, got {}", query_header.query_count());
 			header.set_response_code(ResponseCode::FormErr);
-			return mk_msg(header, Some(q), answers);
+			return self.mk_msg(&trace, header, Some(q), answers);
 		}
 
 		// to do: handle edns (RFC 6891)
@@ -101,29 +426,34 @@ impl Diverge {
 
 		match q.query_class() {
 			DNSClass::IN => match q.query_type() {
-				RecordType::A => {
-					let name = q.name();
-					info!("A {}", name);
-					answers = Some(self.query_ip(name, RecordType::A).await);
-				}
-				RecordType::AAAA => {
-					let name = q.name();
-					info!("AAAA {}", name);
-					answers = Some(self.query_ip(name, RecordType::AAAA).await);
-				}
 				RecordType::PTR => {
 					if let Some(a) = parse_ptr_verbose(&q.name().to_ascii()) {
 						info!("PTR {}", a);
-						answers = self.query_ptr(a).await;
+						answers = self.query_ptr(view, &trace, a).await;
 					} else {
 						header.set_response_code(ResponseCode::FormErr);
 					}
 				}
-				_ => {
-					let qtype = q.query_type();
+				qtype => {
 					let name = q.name();
 					info!("{} {}", qtype, name);
-					answers = self.query_other(name, qtype).await;
+					match self.domain_map_get(view, &trace, name) {
+						None => {
+							header.set_response_code(ResponseCode::ServFail);
+						}
+						Some(hit) => match qtype {
+							RecordType::A
+							| RecordType::AAAA
+							| RecordType::HTTPS
+							| RecordType::SVCB => match self.query_ip(view, &trace, name, qtype, hit).await {
+								Ok(r) => answers = Some(r),
+								Err(rcode) => {
+									header.set_response_code(rcode);
+								}
+							},
+							_ => answers = self.query_other(view, &trace, name, qtype, hit).await,
+						},
+					}
 				}
 			},
 			DNSClass::CH => {
@@ -136,32 +466,87 @@ impl Diverge {
 				header.set_response_code(ResponseCode::NotImp);
 			}
 		}
-		mk_msg(header, Some(q), answers)
+		self.mk_msg(&trace, header, Some(q), answers)
+	}
+
+	// domain map lookup, taking down upstreams into account
+	//	None means SERVFAIL
+	fn domain_map_get(&self, view: &View, trace: &Trace, name: &Name) -> Option<Option<u8>> {
+		match self.domain_map_down(view, name) {
+			Ok(hit) => Some(hit),
+			Err(i) => {
+				self.decide(trace, i as usize, Decision::DomainMap);
+				trace.outcome(i as usize, "down", None);
+				None
+			}
+		}
+	}
+
+	// the down policy part of domain_map_get, leaving the decision to the caller
+	//	Err with the down upstream to SERVFAIL for
+	fn domain_map_down(&self, view: &View, name: &Name) -> Result<Option<u8>, u8> {
+		let Some(i) = view.lists().domain_map.get(&name.to_utf8()) else {
+			return Ok(None);
+		};
+		let upstream = &self.upstreams[i as usize];
+		if !upstream.down.get() {
+			return Ok(Some(i));
+		}
+		match self.down_policy {
+			DownPolicy::Query => {
+				debug!("upstream {} is down, querying anyway", upstream.name);
+				Ok(Some(i))
+			}
+			DownPolicy::Fallback => {
+				info!(
+					"domain map choose upstream {} for {} but it's down, fallback",
+					upstream.name, name
+				);
+				Ok(None)
+			}
+			DownPolicy::ServFail => {
+				info!(
+					"domain map choose upstream {} for {} but it's down, SERVFAIL",
+					upstream.name, name
+				);
+				Err(i)
+			}
+		}
 	}
 
-	// handles A/AAAA
-	async fn query_ip(&self, name: &Name, rtype: RecordType) -> Vec<Record> {
+	// handles A/AAAA, and HTTPS/SVCB by their address hints
+	//	Err with the rcode to respond with instead
+	async fn query_ip(
+		&self,
+		view: &View,
+		trace: &Trace,
+		name: &Name,
+		rtype: RecordType,
+		hit: Option<u8>,
+	) -> Result<Vec<Record>, ResponseCode> {
 		let mut ret = Vec::with_capacity(0x10);
-		if let Some(i) = self.domain_map.get(&name.to_utf8()) {
+		if let Some(i) = hit {
 			let upstream = &self.upstreams[i as usize];
 			if upstream.disable_aaaa && rtype == RecordType::AAAA {
 				info!(
 					"domain map choose upstream {} for {} but AAAA is disabled",
 					upstream.name, name
 				);
-				return ret;
+				self.decide(trace, i as usize, Decision::DomainMap);
+				trace.outcome(i as usize, "skipped", None);
+				return Ok(ret);
 			}
 			info!("domain map choose upstream {} for {}", &upstream.name, name);
-			match lookup_records(upstream.resolver.clone(), name.to_ascii(), rtype).await {
+			self.decide(trace, i as usize, Decision::DomainMap);
+			match self.lookup(trace, i as usize, name.to_ascii(), rtype).await {
 				LookupOutcome::Records(records) => {
-					let c = self.prune(&mut ret, &records, i);
-					if c == 0 {
-						warn!(
-							"domain map choose upstream {} for {} but all records are pruned; returning unfiltered records",
-							upstream.name, &name
-						);
-						ret = records;
+					if let Some(r) = self
+						.follow_cname(view, trace, i as usize, name, rtype, &records)
+						.await
+					{
+						return r;
 					}
+					ret = self.keep_mapped(view, trace, records, i, name);
 				}
 				LookupOutcome::Error(e) => {
 					log_resolve_error(&upstream.name, name, e);
@@ -170,44 +555,69 @@ impl Diverge {
 				LookupOutcome::Skipped => {}
 			}
 		} else {
-			let name = name.to_ascii();
-			let mut outcomes = Vec::with_capacity(self.upstreams.len());
-			outcomes.resize_with(self.upstreams.len(), || None);
+			let ascii = name.to_ascii();
+			// indexed by position in the view
+			let mut outcomes = Vec::with_capacity(view.order.len());
+			outcomes.resize_with(view.order.len(), || None);
 			let mut tasks = FuturesUnordered::new();
+			// HTTPS/SVCB with no hints from any upstream go as a domain map miss would
+			let svcb = matches!(rtype, RecordType::HTTPS | RecordType::SVCB);
+			let mut hinted = false;
+			let mut fallback = None;
 
-			for (i, upstream) in self.upstreams.iter().enumerate() {
+			for (p, &i) in view.order.iter().enumerate() {
+				let upstream = &self.upstreams[i];
 				if upstream.disable_aaaa && rtype == RecordType::AAAA {
-					outcomes[i] = Some(LookupOutcome::Skipped);
+					outcomes[p] = Some(LookupOutcome::Skipped);
+					trace.outcome(i, "skipped", None);
 					continue;
 				}
-				let resolver = upstream.resolver.clone();
-				let name = name.clone();
-				tasks.push(async move { (i, lookup_records(resolver, name, rtype).await) });
+				if upstream.down.get() {
+					debug!("upstream {} is down, skipped", upstream.name);
+					outcomes[p] = Some(LookupOutcome::Skipped);
+					trace.outcome(i, "down", None);
+					continue;
+				}
+				let name = ascii.clone();
+				tasks.push(async move { (p, self.lookup(trace, i, name, rtype).await) });
 			}
 
 			let mut next = 0;
-			while let Some((i, outcome)) = tasks.next().await {
-				outcomes[i] = Some(outcome);
+			while let Some((p, outcome)) = tasks.next().await {
+				outcomes[p] = Some(outcome);
 
 				while next < outcomes.len() {
 					let Some(outcome) = outcomes[next].take() else {
 						break;
 					};
-					let uname = &self.upstreams[next].name;
+					let i = view.order[next];
+					let uname = &self.upstreams[i].name;
 					match outcome {
 						LookupOutcome::Records(records) => {
-							let c = self.prune(&mut ret, &records, next as u8);
+							if let Some(r) = self
+								.follow_cname(view, trace, i, name, rtype, &records)
+								.await
+							{
+								return r;
+							}
+							let c = self.prune(view, trace, &mut ret, &records, i as u8);
 							if c > 0 {
-								info!("ip map choose upstream {} for {}", uname, &name);
-								return ret;
+								info!("ip map choose upstream {} for {}", uname, name);
+								self.decide(trace, i, Decision::IpMap);
+								self.learn(view, trace, name, rtype, i);
+								return Ok(ret);
 							}
 							ret.clear();
+							if svcb {
+								hinted |= records.iter().any(has_hints);
+								fallback.get_or_insert((i, records));
+							}
 						}
 						LookupOutcome::Error(e) => {
-							log_resolve_error(uname, &name, e);
+							log_resolve_error(uname, name, e);
 						}
 						LookupOutcome::Timeout => {
-							log_resolve_timeout(uname, &name, rtype);
+							log_resolve_timeout(uname, name, rtype);
 						}
 						LookupOutcome::Skipped => {}
 					}
@@ -217,53 +627,256 @@ impl Diverge {
 
 			while next < outcomes.len() {
 				if let Some(outcome) = outcomes[next].take() {
-					let uname = &self.upstreams[next].name;
+					let i = view.order[next];
+					let uname = &self.upstreams[i].name;
 					match outcome {
 						LookupOutcome::Records(records) => {
-							let c = self.prune(&mut ret, &records, next as u8);
+							if let Some(r) = self
+								.follow_cname(view, trace, i, name, rtype, &records)
+								.await
+							{
+								return r;
+							}
+							let c = self.prune(view, trace, &mut ret, &records, i as u8);
 							if c > 0 {
-								info!("ip map choose upstream {} for {}", uname, &name);
-								return ret;
+								info!("ip map choose upstream {} for {}", uname, name);
+								self.decide(trace, i, Decision::IpMap);
+								self.learn(view, trace, name, rtype, i);
+								return Ok(ret);
 							}
 							ret.clear();
+							if svcb {
+								hinted |= records.iter().any(has_hints);
+								fallback.get_or_insert((i, records));
+							}
 						}
 						LookupOutcome::Error(e) => {
-							log_resolve_error(uname, &name, e);
+							log_resolve_error(uname, name, e);
 						}
-						LookupOutcome::Timeout => log_resolve_timeout(uname, &name, rtype),
+						LookupOutcome::Timeout => log_resolve_timeout(uname, name, rtype),
 						LookupOutcome::Skipped => {}
 					};
 				}
 				next += 1;
 			}
+
+			if let Some((i, records)) = fallback.filter(|_| !hinted) {
+				info!(
+					"no address hints for {}, fallback to upstream {}",
+					name, self.upstreams[i].name
+				);
+				self.decide(trace, i, Decision::Fallback);
+				return Ok(records);
+			}
+		}
+		Ok(ret)
+	}
+
+	// learned names go to the upstreams' own lists, which the default view has
+	//	only from A/AAAA, a hint is no more than a hint
+	fn learn(&self, view: &View, trace: &Trace, name: &Name, rtype: RecordType, i: usize) {
+		if trace.is_explain() || !matches!(rtype, RecordType::A | RecordType::AAAA) {
+			return;
+		}
+		if let Some(learn) = self.learn.as_ref() {
+			if ptr::eq(view, self.views.last().unwrap()) {
+				learn.record(&name.to_ascii(), i);
+			}
+		}
+	}
+
+	// records of the upstream the domain map chose
+	fn keep_mapped(
+		&self,
+		view: &View,
+		trace: &Trace,
+		records: Vec<Record>,
+		i: u8,
+		name: &Name,
+	) -> Vec<Record> {
+		let mut ret = Vec::with_capacity(records.len());
+		if self.prune(view, trace, &mut ret, &records, i) == 0 {
+			warn!(
+				"domain map choose upstream {} for {} but all records are pruned; returning unfiltered records",
+				self.upstreams[i as usize].name, name
+			);
+			return records;
 		}
 		ret
 	}
 
-	// prune A/AAAA records, retain the rest, and return the number of remain A/AAAA records
-	fn prune(&self, ret: &mut Vec<Record>, records: &[Record], v: u8) -> usize {
+	// applies the domain map to CNAME targets in the records from upstream i
+	//	Some with the answer to use instead, if one is mapped to another upstream
+	//	whose answer is taken as is, without following its CNAMEs
+	//	or Some(Err) to SERVFAIL, if that upstream is down under the servfail policy, or discarded
+	async fn follow_cname(
+		&self,
+		view: &View,
+		trace: &Trace,
+		i: usize,
+		name: &Name,
+		rtype: RecordType,
+		records: &[Record],
+	) -> Option<Result<Vec<Record>, ResponseCode>> {
+		if self.cname_policy == CnamePolicy::Ignore {
+			return None;
+		}
+		let mut chain = Vec::new();
+		let mut current = name.clone();
+		for _ in 0..MAX_CNAME_CHAIN {
+			let (r, target) = records.iter().find_map(|r| match r.data() {
+				Some(RData::CNAME(c)) if r.name() == &current => Some((r, c.0.clone())),
+				_ => None,
+			})?;
+			chain.push(r.clone());
+			let j = match self.domain_map_down(view, &target) {
+				// mapped to a down upstream, SERVFAIL policy
+				Err(j) => {
+					self.decide(trace, j as usize, Decision::Cname);
+					trace.outcome(j as usize, "down", None);
+					return Some(Err(ResponseCode::ServFail));
+				}
+				Ok(Some(j)) if j as usize != i => j as usize,
+				Ok(_) => {
+					current = target;
+					continue;
+				}
+			};
+			let upstream = &self.upstreams[j];
+			if self.cname_policy == CnamePolicy::Discard {
+				info!(
+					"CNAME {} of {} is mapped to upstream {}, discarded",
+					target, name, upstream.name
+				);
+				return Some(Err(ResponseCode::ServFail));
+			}
+			info!(
+				"CNAME {} of {} is mapped to upstream {}, resolving through it",
+				target, name, upstream.name
+			);
+			self.decide(trace, j, Decision::Cname);
+			if upstream.disable_aaaa && rtype == RecordType::AAAA {
+				trace.outcome(j, "skipped", None);
+				return Some(Ok(chain));
+			}
+			match self.lookup(trace, j, target.to_ascii(), rtype).await {
+				LookupOutcome::Records(records) => {
+					chain.extend(self.keep_mapped(view, trace, records, j as u8, &target))
+				}
+				LookupOutcome::Error(e) => log_resolve_error(&upstream.name, &target, e),
+				LookupOutcome::Timeout => log_resolve_timeout(&upstream.name, &target, rtype),
+				LookupOutcome::Skipped => {}
+			}
+			return Some(Ok(chain));
+		}
+		None
+	}
+
+	// prune A/AAAA records and HTTPS/SVCB address hints, retain the rest
+	//	return the number of remain A/AAAA records, and HTTPS/SVCB records with a remain hint
+	//		those without any hint tell nothing of where they're from, so they don't count
+	fn prune(
+		&self,
+		view: &View,
+		trace: &Trace,
+		ret: &mut Vec<Record>,
+		records: &[Record],
+		v: u8,
+	) -> usize {
+		let ip_map = &view.lists().ip_map;
 		let mut c = 0;
+		let mut pruned = 0;
 		for r in records {
 			match (r.dns_class(), r.record_type()) {
 				(DNSClass::IN, RecordType::A) => {
 					let a = r.data().unwrap().as_a().unwrap().0;
-					if self.ip_map.get4(a) == v {
+					if ip_map.get4(a) == v {
 						trace!("keep A {}", a);
 						ret.push(r.to_owned());
 						c += 1;
+						trace.prune(v as usize, true, a.into());
 					} else {
 						trace!("prune A {}", a);
+						pruned += 1;
+						trace.prune(v as usize, false, a.into());
 					}
 				}
 				(DNSClass::IN, RecordType::AAAA) => {
 					let a = r.data().unwrap().as_aaaa().unwrap().0;
-					if self.ip_map.get6(a) == v {
+					if ip_map.get6(a) == v {
 						trace!("keep AAAA {}", a);
 						ret.push(r.to_owned());
 						c += 1;
+						trace.prune(v as usize, true, a.into());
 					} else {
 						trace!("prune AAAA {}", a);
+						pruned += 1;
+						trace.prune(v as usize, false, a.into());
+					}
+				}
+				(DNSClass::IN, RecordType::HTTPS | RecordType::SVCB) => {
+					let svcb = match r.data() {
+						Some(RData::HTTPS(h)) => &h.0,
+						Some(RData::SVCB(s)) => s,
+						_ => {
+							ret.push(r.to_owned());
+							continue;
+						}
+					};
+					let mut hints = 0;
+					let mut kept = 0;
+					let mut keep = |a: IpAddr| {
+						hints += 1;
+						let k = ip_map.get(a) == v;
+						trace!("{} hint {}", if k { "keep" } else { "prune" }, a);
+						trace.prune(v as usize, k, a);
+						if k {
+							kept += 1;
+						} else {
+							pruned += 1;
+						}
+						k
+					};
+					let mut params = Vec::with_capacity(svcb.svc_params().len());
+					for (k, p) in svcb.svc_params() {
+						let p = match p {
+							SvcParamValue::Ipv4Hint(h) => {
+								let h: Vec<_> =
+									h.0.iter().copied().filter(|a| keep(a.0.into())).collect();
+								if h.is_empty() {
+									continue;
+								}
+								SvcParamValue::Ipv4Hint(IpHint(h))
+							}
+							// no IPv6 on that link
+							SvcParamValue::Ipv6Hint(_)
+								if self.upstreams[v as usize].disable_aaaa =>
+							{
+								trace!("drop ipv6hint, AAAA disabled");
+								continue;
+							}
+							SvcParamValue::Ipv6Hint(h) => {
+								let h: Vec<_> =
+									h.0.iter().copied().filter(|a| keep(a.0.into())).collect();
+								if h.is_empty() {
+									continue;
+								}
+								SvcParamValue::Ipv6Hint(IpHint(h))
+							}
+							p => p.clone(),
+						};
+						params.push((*k, p));
 					}
+					if kept > 0 {
+						c += 1;
+					}
+					let svcb = SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params);
+					let mut r = r.to_owned();
+					r.set_data(Some(match r.record_type() {
+						RecordType::HTTPS => RData::HTTPS(HTTPS(svcb)),
+						_ => RData::SVCB(svcb),
+					}));
+					ret.push(r);
 				}
 				_ => {
 					trace!("skip {} record", r.record_type());
@@ -271,16 +884,23 @@ impl Diverge {
 				}
 			}
 		}
+		if pruned > 0 {
+			self.metrics.pruned(v as usize, pruned);
+		}
 		c
 	}
 
-	async fn query_ptr(&self, q: IpAddr) -> Option<Vec<Record>> {
-		let i = self.ip_map.get(q);
+	async fn query_ptr(&self, view: &View, trace: &Trace, q: IpAddr) -> Option<Vec<Record>> {
+		let i = view.lists().ip_map.get(q);
 		let upstream = &self.upstreams[i as usize];
 		info!("ip map choose upstream {} for {} PTR", upstream.name, q);
-		let resp = upstream.resolver.reverse_lookup(q).await;
+		self.decide(trace, i as usize, Decision::IpMap);
+		self.metrics.request(i as usize);
+		let t0 = Instant::now();
+		let resp = upstream.resolver().reverse_lookup(q).await;
+		self.record_result(trace, i as usize, t0, resp.as_ref().err());
 		match resp {
-			Ok(resp) => Some(resp.as_lookup().records().to_vec()),
+			Ok(records) => Some(records),
 			Err(err) => {
 				log_resolve_error(&upstream.name, q, err);
 				None
@@ -288,27 +908,52 @@ impl Diverge {
 		}
 	}
 
-	async fn query_other(&self, q: &Name, rtype: RecordType) -> Option<Vec<Record>> {
-		let upstream = match self.domain_map.get(&q.to_utf8()) {
+	async fn query_other(
+		&self,
+		view: &View,
+		trace: &Trace,
+		q: &Name,
+		rtype: RecordType,
+		hit: Option<u8>,
+	) -> Option<Vec<Record>> {
+		let (i, upstream) = match hit {
 			Some(i) => {
 				let u = &self.upstreams[i as usize];
 				info!("domain map choose upstream {} for {} {}", &u.name, q, rtype);
-				u
+				self.decide(trace, i as usize, Decision::DomainMap);
+				(i as usize, u)
 			}
 			None => {
-				let u = &self.upstreams[0];
+				// the first one that's not down
+				let i = view
+					.order
+					.iter()
+					.copied()
+					.find(|&i| !self.upstreams[i].down.get())
+					.unwrap_or(view.order[0]);
+				let u = &self.upstreams[i];
 				info!(
 					"domain map miss, fallback to upstream {} for {} {}",
 					&u.name, q, rtype
 				);
-				u
+				self.decide(trace, i, Decision::Fallback);
+				(i, u)
 			}
 		};
-		// CAUTION: hickory warned this interface may change in the future
+		self.metrics.request(i);
+		let t0 = Instant::now();
+		let ecs = self.upstream_ecs(trace, i);
 		// interesting, hickory_proto::rr::Name does not satisfy hickory_resolver::IntoName
-		let resp = upstream.resolver.lookup(q.to_ascii(), rtype).await;
+		let resp = upstream
+			.resolver()
+			.lookup_ecs(&q.to_ascii(), rtype, ecs)
+			.await;
+		self.record_result(trace, i, t0, resp.as_ref().err());
 		match resp {
-			Ok(resp) => Some(resp.records().to_vec()),
+			Ok((records, scope)) => {
+				self.record_scope(trace, i, ecs, scope);
+				Some(records)
+			}
 			Err(err) => {
 				log_resolve_error(&upstream.name, q, err);
 				None
@@ -317,6 +962,80 @@ impl Diverge {
 	}
 }
 
+// for the admin API
+impl Diverge {
+	// an actual resolution, just like a query, with the trace returned
+	pub async fn explain_name(&self, name: &Name, rtype: RecordType, client: SocketAddr) -> Value {
+		let trace = Trace::explain(client);
+		trace.question(name.to_ascii(), rtype);
+		let view = self.view(client.ip());
+		let matched = view
+			.lists()
+			.domain_map
+			.get_entry(&name.to_utf8())
+			.map(|(k, _)| k.to_string());
+		let answers = match (rtype, self.domain_map_get(view, &trace, name)) {
+			(_, None) => None,
+			(
+				RecordType::A | RecordType::AAAA | RecordType::HTTPS | RecordType::SVCB,
+				Some(hit),
+			) => self.query_ip(view, &trace, name, rtype, hit).await.ok(),
+			(_, Some(hit)) => self.query_other(view, &trace, name, rtype, hit).await,
+		};
+		let mut v = trace.to_json(&self.upstream_names());
+		v["view"] = json!(view.name);
+		v["domain_map_entry"] = json!(matched);
+		v["answers"] = json!(answers
+			.unwrap_or_default()
+			.iter()
+			.map(|r| r.to_string())
+			.collect::<Vec<_>>());
+		v
+	}
+
+	pub fn explain_ip(&self, ip: IpAddr, client: SocketAddr) -> Value {
+		let view = self.view(client.ip());
+		let (prefix, i) = view.lists().ip_map.get_entry(ip);
+		json!({
+			"ip": ip,
+			"view": view.name,
+			"ip_map_entry": prefix.map(|(a, l)| format!("{}/{}", a, l)),
+			"upstream": self.upstreams[i as usize].name,
+		})
+	}
+
+	pub fn upstreams_json(&self) -> Value {
+		let now = Instant::now();
+		let ago = |t: Option<Instant>| t.map(|t| (now - t).as_secs_f64());
+		self.upstreams
+			.iter()
+			.enumerate()
+			.map(|(i, u)| {
+				let (requests, errors, timeouts) = self.metrics.upstream_counters(i);
+				json!({
+					"name": u.name,
+					"protocol": u.conf.protocol.to_string(),
+					"addresses": *u.addrs.borrow(),
+					"hosts": u.conf.hosts,
+					"port": u.conf.port,
+					"disable_aaaa": u.disable_aaaa,
+					"requests": requests,
+					"errors": errors,
+					"timeouts": timeouts,
+					"last_success_secs_ago": ago(u.last_success.get()),
+					"last_failure_secs_ago": ago(u.last_failure.get()),
+					"consecutive_failures": u.failures.get(),
+					"down": u.down.get(),
+				})
+			})
+			.collect()
+	}
+
+	fn upstream_names(&self) -> Vec<String> {
+		self.upstreams.iter().map(|u| u.name.clone()).collect()
+	}
+}
+
 enum LookupOutcome {
 	Records(Vec<Record>),
 	Error(ResolveError),
@@ -324,19 +1043,229 @@ enum LookupOutcome {
 	Skipped,
 }
 
-async fn lookup_records(
-	resolver: TokioAsyncResolver,
-	name: String,
-	rtype: RecordType,
-) -> LookupOutcome {
-	match timeout(UPSTREAM_LOOKUP_TIMEOUT, resolver.lookup(name, rtype)).await {
-		Ok(Ok(resp)) => LookupOutcome::Records(resp.records().to_vec()),
-		Ok(Err(e)) => LookupOutcome::Error(e),
-		Err(_) => LookupOutcome::Timeout,
+impl Diverge {
+	async fn lookup(
+		&self,
+		trace: &Trace,
+		i: usize,
+		name: String,
+		rtype: RecordType,
+	) -> LookupOutcome {
+		let resolver = self.upstreams[i].resolver();
+		self.metrics.request(i);
+		let t0 = Instant::now();
+		let ecs = self.upstream_ecs(trace, i);
+		match timeout(
+			UPSTREAM_LOOKUP_TIMEOUT,
+			resolver.lookup_ecs(&name, rtype, ecs),
+		)
+		.await
+		{
+			Ok(Ok((records, scope))) => {
+				self.record_result(trace, i, t0, None);
+				self.record_scope(trace, i, ecs, scope);
+				LookupOutcome::Records(records)
+			}
+			Ok(Err(e)) => {
+				self.record_result(trace, i, t0, Some(&e));
+				LookupOutcome::Error(e)
+			}
+			Err(_) => {
+				self.metrics.timeout(i);
+				self.failed(i);
+				trace.outcome(i, "timeout", None);
+				LookupOutcome::Timeout
+			}
+		}
+	}
+
+	// the ECS option to send upstream i
+	fn upstream_ecs(&self, trace: &Trace, i: usize) -> Option<Subnet> {
+		self.upstreams[i].conf.ecs.query(trace.ecs())
+	}
+
+	// only an answer for the client's own subnet is scoped for the client
+	fn record_scope(&self, trace: &Trace, i: usize, sent: Option<Subnet>, resp: Option<Subnet>) {
+		if self.upstreams[i].conf.ecs != Ecs::Pass {
+			return;
+		}
+		match (sent, resp) {
+			(Some(sent), Some(resp)) if sent.answered_by(&resp) => trace.scope(i, resp.scope),
+			(Some(sent), Some(resp)) => debug!(
+				"upstream {} answered ECS {} to {}, ignored",
+				self.upstreams[i].name, resp, sent
+			),
+			_ => {}
+		}
+	}
+
+	fn record_result(&self, trace: &Trace, i: usize, t0: Instant, err: Option<&ResolveError>) {
+		let elapsed = t0.elapsed();
+		match err.map(|e| e.kind()) {
+			None => {
+				self.metrics.answered(i, elapsed);
+				self.succeeded(i);
+				trace.outcome(i, "records", Some(elapsed));
+			}
+			Some(ResolveErrorKind::NoRecordsFound { .. }) => {
+				self.metrics.answered(i, elapsed);
+				self.succeeded(i);
+				trace.outcome(i, "no_records", Some(elapsed));
+			}
+			Some(_) => {
+				self.metrics.error(i, elapsed);
+				self.failed(i);
+				trace.outcome(i, "error", Some(elapsed));
+			}
+		}
+	}
+
+	fn succeeded(&self, i: usize) {
+		let upstream = &self.upstreams[i];
+		upstream.last_success.set(Some(Instant::now()));
+		upstream.failures.set(0);
+		if upstream.down.replace(false) {
+			info!("upstream {} recovered", upstream.name);
+			self.metrics.down(i, false);
+		}
+	}
+
+	fn failed(&self, i: usize) {
+		let upstream = &self.upstreams[i];
+		upstream.last_failure.set(Some(Instant::now()));
+		let failures = upstream.failures.get() + 1;
+		upstream.failures.set(failures);
+		if self.circuit_threshold > 0
+			&& failures >= self.circuit_threshold
+			&& !upstream.down.replace(true)
+		{
+			warn!(
+				"upstream {} is down after {} consecutive failures",
+				upstream.name, failures
+			);
+			self.metrics.down(i, true);
+		}
+	}
+
+	// probe upstreams that are down, until they recover
+	pub async fn probe(self: Rc<Self>) {
+		if self.circuit_threshold == 0 {
+			return;
+		}
+		let mut intv = interval(self.probe_interval);
+		intv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		loop {
+			intv.tick().await;
+			for (i, upstream) in self.upstreams.iter().enumerate() {
+				if !upstream.down.get() {
+					continue;
+				}
+				trace!("probing upstream {}", upstream.name);
+				let r = timeout(
+					UPSTREAM_LOOKUP_TIMEOUT,
+					upstream.resolver().lookup(&self.probe_name, RecordType::NS),
+				)
+				.await;
+				match r {
+					Ok(Ok(_)) => self.succeeded(i),
+					Ok(Err(e)) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+						self.succeeded(i)
+					}
+					Ok(Err(e)) => debug!("upstream {} probe failed: {}", upstream.name, e),
+					Err(_) => debug!("upstream {} probe timed out", upstream.name),
+				}
+			}
+		}
+	}
+
+	// resolve hostname addresses through the bootstrap upstream
+	//	and rebuild the resolvers whose addresses changed
+	pub async fn bootstrap(&self) {
+		let Some(b) = self.bootstrap else {
+			return;
+		};
+		let resolver = self.upstreams[b].resolver();
+		for upstream in self.upstreams.iter().filter(|u| !u.conf.hosts.is_empty()) {
+			let mut addrs = upstream.conf.addrs.clone();
+			for host in upstream.conf.hosts.iter() {
+				for rtype in [RecordType::A, RecordType::AAAA] {
+					match timeout(UPSTREAM_LOOKUP_TIMEOUT, resolver.lookup(host, rtype)).await {
+						Ok(Ok(records)) => {
+							for a in records.iter().filter_map(|r| r.data()?.ip_addr()) {
+								if !addrs.contains(&a) {
+									addrs.push(a);
+								}
+							}
+						}
+						Ok(Err(e))
+							if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {}
+						Ok(Err(e)) => warn!("bootstrap {} {} error: {}", host, rtype, e),
+						Err(_) => warn!("bootstrap {} {} timed out", host, rtype),
+					}
+				}
+			}
+			if addrs.is_empty() {
+				warn!(
+					"upstream {}: failed to resolve {:?}",
+					upstream.name, upstream.conf.hosts
+				);
+				continue;
+			}
+			if *upstream.addrs.borrow() == addrs {
+				continue;
+			}
+			info!("upstream {} addresses: {:?}", upstream.name, addrs);
+			let conf = UpstreamSec {
+				addrs: addrs.clone(),
+				..upstream.conf.clone()
+			};
+			*upstream.resolver.borrow_mut() = Rc::new(resolver::from(&conf));
+			*upstream.addrs.borrow_mut() = addrs;
+		}
+	}
+
+	// re-resolve hostname addresses periodically, the first round is done on start
+	pub async fn rebootstrap(self: Rc<Self>) {
+		if self.bootstrap.is_none() {
+			return;
+		}
+		let mut intv = interval(self.bootstrap_interval);
+		intv.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		intv.tick().await;
+		loop {
+			intv.tick().await;
+			self.bootstrap().await;
+		}
+	}
+
+	fn decide(&self, trace: &Trace, i: usize, decision: Decision) {
+		if !trace.is_explain() {
+			self.metrics.decision(i, decision);
+		}
+		trace.decision(i, decision);
+	}
+
+	fn mk_msg(
+		&self,
+		trace: &Trace,
+		header: Header,
+		q: Option<&Query>,
+		answers: Option<Vec<Record>>,
+	) -> Option<Vec<u8>> {
+		self.metrics.response(header.response_code());
+		if let Some(query_log) = self.query_log.as_ref() {
+			query_log.write(trace, header.response_code());
+		}
+		mk_msg(header, q, answers, trace.ecs_response())
 	}
 }
 
-fn mk_msg(header: Header, q: Option<&Query>, answers: Option<Vec<Record>>) -> Option<Vec<u8>> {
+fn mk_msg(
+	header: Header,
+	q: Option<&Query>,
+	answers: Option<Vec<Record>>,
+	ecs: Option<Subnet>,
+) -> Option<Vec<u8>> {
 	let mut resp = Message::new();
 	resp.set_header(header);
 	if let Some(q) = q {
@@ -345,6 +1274,13 @@ fn mk_msg(header: Header, q: Option<&Query>, answers: Option<Vec<Record>>) -> Op
 	if let Some(a) = answers {
 		resp.add_answers(a);
 	}
+	// RFC 7871 7.2.2, clients that sent ECS get it back, with the scope
+	if let Some(ecs) = ecs {
+		let mut edns = Edns::new();
+		edns.set_max_payload(1232);
+		edns.options_mut().insert(ecs.to_option());
+		resp.set_edns(edns);
+	}
 	// it seems finalize() is not necessary
 	trace!("dns response: {}", resp);
 	// to do: truncate if exceed 0xffff
@@ -353,6 +1289,17 @@ fn mk_msg(header: Header, q: Option<&Query>, answers: Option<Vec<Record>>) -> Op
 		.ok()
 }
 
+fn has_hints(r: &Record) -> bool {
+	let svcb = match r.data() {
+		Some(RData::HTTPS(h)) => &h.0,
+		Some(RData::SVCB(s)) => s,
+		_ => return false,
+	};
+	svcb.svc_params()
+		.iter()
+		.any(|(_, p)| matches!(p, SvcParamValue::Ipv4Hint(_) | SvcParamValue::Ipv6Hint(_)))
+}
+
 fn parse_ptr_verbose(q: &str) -> Option<IpAddr> {
 	let ptr = parse_ptr(q);
 	if ptr.is_none() {
@@ -427,7 +1374,7 @@ fn log_resolve_error<N: std::fmt::Display>(upname: &str, name: N, err: ResolveEr
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use crate::conf::{DivergeConf, GlobalSec, UpstreamSec};
+	use crate::conf::{DivergeConf, DownPolicy, GlobalSec, UpstreamSec};
 	use hickory_proto::op::OpCode;
 	use hickory_resolver::config::Protocol;
 	use tokio::net::UdpSocket;
@@ -451,38 +1398,32 @@ mod tests {
 		let responsive = no_records_server().await;
 		let hanging = hanging_server().await;
 		let diverge = Diverge::from(&DivergeConf {
-			global: GlobalSec {
-				listen: "127.0.0.1:0".parse().unwrap(),
-			},
+			global: GlobalSec::new(),
 			upstreams: vec![
 				UpstreamSec {
-					name: "CN".to_string(),
 					protocol: Protocol::Udp,
 					addrs: vec![responsive.ip()],
 					port: Some(responsive.port()),
-					tls_dns_name: None,
-					ips: vec![],
-					domains: vec![],
-					disable_aaaa: false,
+					..UpstreamSec::new("CN")
 				},
 				UpstreamSec {
-					name: "X".to_string(),
 					protocol: Protocol::Udp,
 					addrs: vec![hanging.ip()],
 					port: Some(hanging.port()),
-					tls_dns_name: None,
-					ips: vec![],
-					domains: vec![],
-					disable_aaaa: false,
+					..UpstreamSec::new("X")
 				},
 			],
+			views: Vec::new(),
 		});
 
 		let query = query_message("api.github.com.", RecordType::AAAA);
-		let response = timeout(Duration::from_secs(5), diverge.query(query))
-			.await
-			.expect("diverge query should be bounded by upstream timeout")
-			.expect("valid query should produce a DNS response");
+		let response = timeout(
+			Duration::from_secs(5),
+			diverge.query(query, "127.0.0.1:5353".parse().unwrap()),
+		)
+		.await
+		.expect("diverge query should be bounded by upstream timeout")
+		.expect("valid query should produce a DNS response");
 		let response = Message::from_vec(&response).unwrap();
 
 		assert_eq!(response.response_code(), ResponseCode::NoError);
@@ -490,6 +1431,281 @@ mod tests {
 		assert_eq!(response.query_count(), 1);
 	}
 
+	#[tokio::test(flavor = "current_thread")]
+	async fn query_skips_upstream_once_down() {
+		let responsive = no_records_server().await;
+		let hanging = hanging_server().await;
+		let diverge = Diverge::from(&DivergeConf {
+			global: GlobalSec {
+				circuit_threshold: 1,
+				..GlobalSec::new()
+			},
+			upstreams: vec![
+				UpstreamSec {
+					addrs: vec![responsive.ip()],
+					port: Some(responsive.port()),
+					..UpstreamSec::new("CN")
+				},
+				UpstreamSec {
+					addrs: vec![hanging.ip()],
+					port: Some(hanging.port()),
+					..UpstreamSec::new("X")
+				},
+			],
+			views: Vec::new(),
+		});
+		let client = "127.0.0.1:5353".parse().unwrap();
+
+		// the first one times out on X
+		let query = query_message("api.github.com.", RecordType::AAAA);
+		diverge.query(query.clone(), client).await.unwrap();
+		assert!(diverge.upstreams[1].down.get());
+
+		// now X should be skipped
+		let response = timeout(Duration::from_millis(500), diverge.query(query, client))
+			.await
+			.expect("down upstream should be skipped")
+			.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.response_code(), ResponseCode::NoError);
+		assert_eq!(response.answer_count(), 0);
+	}
+
+	#[tokio::test(flavor = "current_thread")]
+	async fn query_uses_client_view() {
+		let hanging = hanging_server().await;
+		let responsive = no_records_server().await;
+		let diverge = Diverge::from(&DivergeConf {
+			global: GlobalSec::new(),
+			upstreams: vec![
+				UpstreamSec {
+					addrs: vec![hanging.ip()],
+					port: Some(hanging.port()),
+					..UpstreamSec::new("0")
+				},
+				UpstreamSec {
+					addrs: vec![responsive.ip()],
+					port: Some(responsive.port()),
+					..UpstreamSec::new("X")
+				},
+			],
+			views: vec![ViewSec {
+				clients: vec![("192.0.2.0".parse().unwrap(), 24)],
+				upstreams: vec!["X".to_string()],
+				..ViewSec::new("guest")
+			}],
+		});
+
+		// the guest view only has X, so 0 hanging doesn't matter
+		let query = query_message("api.github.com.", RecordType::A);
+		let response = timeout(
+			Duration::from_millis(500),
+			diverge.query(query.clone(), "192.0.2.7:5353".parse().unwrap()),
+		)
+		.await
+		.expect("guest view should not wait for upstream 0")
+		.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.response_code(), ResponseCode::NoError);
+
+		// others wait for 0 first
+		let r = timeout(
+			Duration::from_millis(500),
+			diverge.query(query, "127.0.0.1:5353".parse().unwrap()),
+		)
+		.await;
+		assert!(r.is_err());
+	}
+
+	#[tokio::test(flavor = "current_thread")]
+	async fn query_follows_cname_to_mapped_upstream() {
+		let www = Name::from_ascii("www.example.com.").unwrap();
+		let edge = Name::from_ascii("e1.akamaiedge.net.").unwrap();
+		let cname = Record::from_rdata(
+			www.clone(),
+			60,
+			RData::CNAME(hickory_proto::rr::rdata::CNAME(edge.clone())),
+		);
+		let a = |name: &Name, ip: &str| {
+			Record::from_rdata(name.clone(), 60, RData::A(ip.parse().unwrap()))
+		};
+		let first = records_server(vec![cname.clone(), a(&edge, "192.0.2.1")]).await;
+		let mapped = records_server(vec![a(&edge, "198.51.100.7")]).await;
+
+		let list = std::env::temp_dir().join(format!("diverge-cname-{}.lst", std::process::id()));
+		std::fs::write(&list, "akamaiedge.net\n").unwrap();
+		let conf = |cname_policy| DivergeConf {
+			global: GlobalSec {
+				cname_policy,
+				..GlobalSec::new()
+			},
+			upstreams: vec![
+				UpstreamSec {
+					addrs: vec![first.ip()],
+					port: Some(first.port()),
+					..UpstreamSec::new("0")
+				},
+				UpstreamSec {
+					addrs: vec![mapped.ip()],
+					port: Some(mapped.port()),
+					domains: vec![list.to_str().unwrap().to_string()],
+					..UpstreamSec::new("X")
+				},
+			],
+			views: Vec::new(),
+		};
+		let client = "127.0.0.1:5353".parse().unwrap();
+		let query = query_message("www.example.com.", RecordType::A);
+
+		let diverge = Diverge::from(&conf(CnamePolicy::Resolve));
+		let response = diverge.query(query.clone(), client).await.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		let answers: Vec<_> = response
+			.answers()
+			.iter()
+			.map(|r| r.data().unwrap().clone())
+			.collect();
+		assert_eq!(
+			answers,
+			vec![
+				cname.data().unwrap().clone(),
+				RData::A("198.51.100.7".parse().unwrap())
+			]
+		);
+
+		let diverge = Diverge::from(&conf(CnamePolicy::Discard));
+		let response = diverge.query(query.clone(), client).await.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.response_code(), ResponseCode::ServFail);
+		assert_eq!(response.answer_count(), 0);
+
+		// the target's upstream is down, SERVFAIL policy
+		let mut c = conf(CnamePolicy::Resolve);
+		c.global.down_policy = DownPolicy::ServFail;
+		let diverge = Diverge::from(&c);
+		diverge.upstreams[1].down.set(true);
+		let response = diverge.query(query, client).await.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.response_code(), ResponseCode::ServFail);
+		assert_eq!(response.answer_count(), 0);
+		let metrics = diverge.metrics().render();
+		assert!(metrics.contains("diverge_decisions_total{upstream=\"X\",method=\"cname\"} 1"));
+		assert!(!metrics.contains("method=\"domain_map\""));
+
+		std::fs::remove_file(&list).unwrap();
+	}
+
+	#[tokio::test(flavor = "current_thread")]
+	async fn query_prunes_https_hints() {
+		// 0 has no hint of its own, X has one of each
+		let first = records_server(vec![https_record(&["198.51.100.1"])]).await;
+		let last = records_server(vec![https_record(&[
+			"192.0.2.9",
+			"198.51.100.2",
+			"2001:db8::2",
+		])])
+		.await;
+		let mut diverge = hints_diverge(first, last);
+		let client = "127.0.0.1:5353".parse().unwrap();
+		let query = query_message("www.example.com.", RecordType::HTTPS);
+
+		let response = diverge.query(query.clone(), client).await.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(
+			response.answers(),
+			&[https_record(&["198.51.100.2", "2001:db8::2"])]
+		);
+
+		// no IPv6 on X
+		diverge.upstreams[1].disable_aaaa = true;
+		let response = diverge.query(query, client).await.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.answers(), &[https_record(&["198.51.100.2"])]);
+	}
+
+	#[tokio::test(flavor = "current_thread")]
+	async fn query_skips_https_without_hints() {
+		let client = "127.0.0.1:5353".parse().unwrap();
+		let query = query_message("www.example.com.", RecordType::HTTPS);
+		let hintless = records_server(vec![https_record(&[])]).await;
+
+		// 0 doesn't win without a hint, X does with one
+		let hinted = records_server(vec![https_record(&["198.51.100.2"])]).await;
+		let response = hints_diverge(hintless, hinted)
+			.query(query.clone(), client)
+			.await
+			.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.answers(), &[https_record(&["198.51.100.2"])]);
+
+		// no hints at all, 0's answer as is
+		let alpn = records_server(vec![https_record(&[]), https_record(&[])]).await;
+		let response = hints_diverge(alpn, hintless)
+			.query(query, client)
+			.await
+			.unwrap();
+		let response = Message::from_vec(&response).unwrap();
+		assert_eq!(response.response_code(), ResponseCode::NoError);
+		assert_eq!(response.answer_count(), 2);
+	}
+
+	// upstream 0 with 192.0.2.0/24 in its ip list, then X
+	fn hints_diverge(first: std::net::SocketAddr, last: std::net::SocketAddr) -> Diverge {
+		let list = std::env::temp_dir().join(format!(
+			"diverge-hints-{}-{}.lst",
+			std::process::id(),
+			first.port()
+		));
+		std::fs::write(&list, "192.0.2.0/24\n").unwrap();
+		let diverge = Diverge::from(&DivergeConf {
+			global: GlobalSec::new(),
+			upstreams: vec![
+				UpstreamSec {
+					addrs: vec![first.ip()],
+					port: Some(first.port()),
+					ips: vec![list.to_str().unwrap().to_string()],
+					..UpstreamSec::new("0")
+				},
+				UpstreamSec {
+					addrs: vec![last.ip()],
+					port: Some(last.port()),
+					..UpstreamSec::new("X")
+				},
+			],
+			views: Vec::new(),
+		});
+		std::fs::remove_file(&list).unwrap();
+		diverge
+	}
+
+	// an HTTPS record of www.example.com. with alpn and the hints, if any
+	fn https_record(hints: &[&str]) -> Record {
+		use hickory_proto::rr::rdata::{
+			svcb::{Alpn, SvcParamKey},
+			A, AAAA,
+		};
+		let v4: Vec<_> = hints.iter().filter_map(|h| h.parse().ok().map(A)).collect();
+		let v6: Vec<_> = hints
+			.iter()
+			.filter_map(|h| h.parse().ok().map(AAAA))
+			.collect();
+		let mut params = vec![(
+			SvcParamKey::Alpn,
+			SvcParamValue::Alpn(Alpn(vec!["h2".to_string()])),
+		)];
+		if !v4.is_empty() {
+			params.push((SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(v4))));
+		}
+		if !v6.is_empty() {
+			params.push((SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(v6))));
+		}
+		Record::from_rdata(
+			Name::from_ascii("www.example.com.").unwrap(),
+			60,
+			RData::HTTPS(HTTPS(SVCB::new(1, Name::root(), params))),
+		)
+	}
+
 	fn query_message(name: &str, rtype: RecordType) -> Vec<u8> {
 		let mut query = Query::new();
 		query.set_name(Name::from_ascii(name).unwrap());
@@ -527,6 +1743,36 @@ mod tests {
 		addr
 	}
 
+	// answers with the records along the CNAME chain from the query name
+	async fn records_server(records: Vec<Record>) -> std::net::SocketAddr {
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = socket.local_addr().unwrap();
+		tokio::spawn(async move {
+			let mut buf = vec![0u8; 512];
+			while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+				let request = Message::from_vec(&buf[..len]).unwrap();
+				let mut response = Message::new();
+				response.set_header(Header::response_from_request(request.header()));
+				response.set_recursion_available(true);
+				let mut name = request.queries()[0].name().clone();
+				for r in &records {
+					if r.name() == &name {
+						response.add_answer(r.clone());
+						if let Some(RData::CNAME(c)) = r.data() {
+							name = c.0.clone();
+						}
+					}
+				}
+				response.add_query(request.queries()[0].clone());
+				socket
+					.send_to(&response.to_vec().unwrap(), peer)
+					.await
+					.unwrap();
+			}
+		});
+		addr
+	}
+
 	async fn hanging_server() -> std::net::SocketAddr {
 		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
 		let addr = socket.local_addr().unwrap();
@@ -2,17 +2,74 @@
 // based on reqwest, has the benefit of supporting proxies
 
 use std::{
+	cell::Cell,
 	net::{IpAddr, SocketAddr},
-	time::Duration,
+	time::{Duration, Instant},
 };
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bytes::Bytes;
 use log::*;
-use reqwest::{header, tls::Version, Client, Proxy, Url};
+use reqwest::{header, tls, Client, Method, Proxy, Url, Version};
+use tokio::time::timeout;
+
+use crate::bind::BindOpts;
+
+// QUIC is often blocked, without any ICMP, so a timeout is all we get
+const H3_TIMEOUT: Duration = Duration::from_secs(1);
+// stay on HTTP/2 for a while after HTTP/3 failed
+const H3_RETRY: Duration = Duration::from_secs(300);
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone)]
+pub struct DohcOpts {
+	pub path: String,
+	// GET or POST
+	pub method: Method,
+	// extra request headers, for authenticated endpoints
+	pub headers: Vec<(String, String)>,
+	pub proxy: Option<String>,
+	pub pool_size: usize,
+	pub pool_idle_timeout: Duration,
+	// for both TCP and HTTP/2 keep-alive
+	pub keepalive: Duration,
+	pub keepalive_timeout: Duration,
+	// try HTTP/3 first, fallback to HTTP/2
+	pub http3: bool,
+}
+
+impl Default for DohcOpts {
+	fn default() -> Self {
+		Self {
+			path: "/dns-query".to_string(),
+			method: Method::POST,
+			headers: Vec::new(),
+			proxy: None,
+			pool_size: 1,
+			pool_idle_timeout: Duration::from_secs(2501),
+			keepalive: Duration::from_secs_f32(25.01),
+			keepalive_timeout: Duration::from_secs_f32(2.501),
+			http3: false,
+		}
+	}
+}
+
+impl DohcOpts {
+	// a plain RFC 8484 POST to /dns-query, which hickory can do too
+	pub fn is_default_request(&self) -> bool {
+		self.path == "/dns-query"
+			&& self.method == Method::POST
+			&& self.headers.is_empty()
+			&& !self.http3
+	}
+}
 
 pub struct Dohc {
 	url: Url,
+	method: Method,
 	reqwest_client: Client,
+	h3_client: Option<Client>,
+	h3_failed: Cell<Option<Instant>>,
 }
 
 impl Dohc {
@@ -20,54 +77,128 @@ impl Dohc {
 		host: impl AsRef<str>,
 		addrs: impl AsRef<[IpAddr]>,
 		port: Option<u16>,
-		proxy: Option<impl AsRef<str>>,
+		opts: &DohcOpts,
+		bind: &BindOpts,
 	) -> Self {
 		let mut headers = header::HeaderMap::new();
-		headers.insert(
-			header::CONTENT_TYPE,
-			"application/dns-message".parse().unwrap(),
-		);
+		headers.insert(header::ACCEPT, "application/dns-message".parse().unwrap());
+		for (k, v) in opts.headers.iter() {
+			let k = header::HeaderName::from_bytes(k.as_bytes())
+				.unwrap_or_else(|e| panic!("invalid header name {}: {}", k, e));
+			let v = header::HeaderValue::from_str(v)
+				.unwrap_or_else(|e| panic!("invalid header value {}: {}", v, e));
+			headers.append(k, v);
+		}
 
-		let mut url = Url::parse(&format!("https://{}/dns-query", host.as_ref())).unwrap();
+		let mut url = Url::parse(&format!("https://{}", host.as_ref())).unwrap();
+		url.set_path(&opts.path);
 		url.set_port(port).unwrap();
 
-		let mut b = Client::builder()
-			.default_headers(headers)
-			.resolve_to_addrs(
-				host.as_ref(),
-				&addrs
-					.as_ref()
-					.iter()
-					.map(|a| SocketAddr::new(*a, 0))
-					.collect::<Vec<SocketAddr>>(),
-			)
-			.pool_idle_timeout(Some(Duration::from_secs(2501)))
-			.pool_max_idle_per_host(1)
-			.tcp_keepalive(Some(Duration::from_secs_f32(25.01)))
-			.min_tls_version(Version::TLS_1_2)
+		let builder = || {
+			let b = Client::builder()
+				.default_headers(headers.clone())
+				.resolve_to_addrs(
+					host.as_ref(),
+					&addrs
+						.as_ref()
+						.iter()
+						.map(|a| SocketAddr::new(*a, 0))
+						.collect::<Vec<SocketAddr>>(),
+				)
+				.pool_idle_timeout(Some(opts.pool_idle_timeout))
+				.pool_max_idle_per_host(opts.pool_size)
+				.local_address(bind.address);
+			// same as BindOpts
+			#[cfg(any(target_os = "linux", target_os = "android"))]
+			let b = match bind.interface.as_ref() {
+				Some(interface) => b.interface(interface),
+				None => b,
+			};
+			b
+		};
+		#[cfg(not(any(target_os = "linux", target_os = "android")))]
+		if bind.interface.is_some() {
+			panic!("interface is only supported on linux");
+		}
+		if bind.fwmark.is_some() {
+			// not implemented, reqwest doesn't give us the socket before connect
+			//	and marking it after would leave the SYN unmarked
+			panic!("fwmark is not implemented for doh-reqwest, see example.conf");
+		}
+
+		let mut b = builder()
+			.tcp_keepalive(Some(opts.keepalive))
+			.min_tls_version(tls::Version::TLS_1_2)
 			.http2_prior_knowledge()
-			.http2_keep_alive_interval(Some(Duration::from_secs_f32(25.01)))
-			.http2_keep_alive_timeout(Duration::from_secs_f32(2.501))
+			.http2_keep_alive_interval(Some(opts.keepalive))
+			.http2_keep_alive_timeout(opts.keepalive_timeout)
 			.http2_keep_alive_while_idle(true);
-
-		if let Some(proxy) = proxy {
-			b = b.proxy(Proxy::all(proxy.as_ref()).unwrap());
+		if let Some(proxy) = opts.proxy.as_ref() {
+			b = b.proxy(Proxy::all(proxy).unwrap());
 		}
 
+		let h3_client = match (opts.http3, opts.proxy.is_some()) {
+			(false, _) => None,
+			(true, true) => {
+				warn!("HTTP/3 doesn't go through proxies, disabled");
+				None
+			}
+			// reqwest doesn't do interface binding for HTTP/3 yet
+			(true, false) if bind.interface.is_some() => {
+				warn!("HTTP/3 doesn't support interface binding, disabled");
+				None
+			}
+			(true, false) => Some(builder().http3_prior_knowledge().build().unwrap()),
+		};
+
 		Self {
 			url,
+			method: opts.method.clone(),
 			reqwest_client: b.build().unwrap(),
+			h3_client,
+			h3_failed: Cell::new(None),
 		}
 	}
 
 	pub async fn exchange(&self, msg: Vec<u8>) -> reqwest::Result<Bytes> {
-		let res = self
-			.reqwest_client
-			.post(self.url.clone())
-			.body(msg)
-			.send()
-			.await?;
-		trace!("reqwest: {}", res.status());
-		res.bytes().await
+		if let Some(h3) = self.h3_client.as_ref() {
+			if self.h3_failed.get().is_none_or(|t| t.elapsed() > H3_RETRY) {
+				match timeout(H3_TIMEOUT, self.send(h3, Version::HTTP_3, msg.clone())).await {
+					Ok(Ok(r)) => {
+						self.h3_failed.set(None);
+						return Ok(r);
+					}
+					// the server did answer
+					Ok(Err(e)) if e.is_status() => return Err(e),
+					Ok(Err(e)) => warn!("HTTP/3 failed, fallback to HTTP/2: {}", e),
+					Err(_) => warn!("HTTP/3 timed out, fallback to HTTP/2"),
+				}
+				self.h3_failed.set(Some(Instant::now()));
+			}
+		}
+		self.send(&self.reqwest_client, Version::HTTP_2, msg).await
+	}
+
+	async fn send(
+		&self,
+		client: &Client,
+		version: Version,
+		msg: Vec<u8>,
+	) -> reqwest::Result<Bytes> {
+		let req = if self.method == Method::GET {
+			// RFC 8484 4.1, base64url without padding
+			let mut url = self.url.clone();
+			url.query_pairs_mut()
+				.append_pair("dns", &URL_SAFE_NO_PAD.encode(&msg));
+			client.get(url)
+		} else {
+			client
+				.post(self.url.clone())
+				.header(header::CONTENT_TYPE, "application/dns-message")
+				.body(msg)
+		};
+		let res = req.version(version).send().await?;
+		trace!("reqwest: {:?} {}", res.version(), res.status());
+		res.error_for_status()?.bytes().await
 	}
 }
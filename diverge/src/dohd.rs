@@ -0,0 +1,170 @@
+// DNS over HTTPS server, RFC 8484
+//	or plain HTTP, behind a reverse proxy
+
+use std::{
+	net::{IpAddr, SocketAddr},
+	rc::Rc,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hickory_proto::op::Message;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+	body::{Bytes, Incoming},
+	header, Method, Request, Response, StatusCode,
+};
+use log::*;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+	acl::Access,
+	diverge::Diverge,
+	httpd::{self, Body, Listen, Peer},
+};
+
+const PATH: &str = "/dns-query";
+const CONTENT_TYPE: &str = "application/dns-message";
+
+pub async fn dohd(listen: Listen, tls: Option<TlsAcceptor>, diverge: Rc<Diverge>) {
+	match tls {
+		Some(_) => info!("serving DoH on {}", listen),
+		None => info!("serving DoH on {}, plain HTTP", listen),
+	}
+	let d = diverge.clone();
+	let stop = async move { d.stopping().await };
+	httpd::serve_tls(listen, tls, stop, move |req| handle(diverge.clone(), req)).await
+}
+
+async fn handle(diverge: Rc<Diverge>, req: Request<Incoming>) -> Response<Body> {
+	if req.uri().path() != PATH {
+		return httpd::status(StatusCode::NOT_FOUND);
+	}
+	let client = client_addr(&req);
+	let access = diverge.admit(client.ip(), false);
+	if access == Access::Drop {
+		return httpd::status(StatusCode::FORBIDDEN);
+	}
+	let q = match *req.method() {
+		Method::GET => {
+			let dns = req.uri().query().and_then(|q| {
+				form_urlencoded::parse(q.as_bytes()).find_map(|(k, v)| {
+					if k == "dns" {
+						Some(v)
+					} else {
+						None
+					}
+				})
+			});
+			// some clients pad it anyway
+			match dns.map(|dns| URL_SAFE_NO_PAD.decode(dns.trim_end_matches('='))) {
+				Some(Ok(q)) => q,
+				_ => return httpd::status(StatusCode::BAD_REQUEST),
+			}
+		}
+		Method::POST => {
+			let ct = req.headers().get(header::CONTENT_TYPE);
+			if ct.is_none_or(|ct| ct != CONTENT_TYPE) {
+				return httpd::status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+			}
+			match Limited::new(req.into_body(), u16::MAX as usize)
+				.collect()
+				.await
+			{
+				Ok(b) => b.to_bytes().to_vec(),
+				Err(e) => {
+					debug!("DoH body error: {}", e);
+					return httpd::status(StatusCode::BAD_REQUEST);
+				}
+			}
+		}
+		_ => return httpd::status(StatusCode::METHOD_NOT_ALLOWED),
+	};
+	let resp = match access {
+		Access::Allow => diverge.query(q, client).await,
+		access => diverge.deny(&q, access),
+	};
+	match resp {
+		Some(resp) => Response::builder()
+			.header(header::CONTENT_TYPE, CONTENT_TYPE)
+			.header(header::CACHE_CONTROL, format!("max-age={}", min_ttl(&resp)))
+			.body(Full::new(Bytes::from(resp)))
+			.unwrap(),
+		None => httpd::status(StatusCode::BAD_REQUEST),
+	}
+}
+
+// the peer, or the last X-Forwarded-For if the peer is a local reverse proxy
+//	which appends the address it sees, those before it are the client's word
+fn client_addr<B>(req: &Request<B>) -> SocketAddr {
+	let peer = req.extensions().get::<Peer>().map(|p| p.0);
+	if peer.is_none_or(|p| p.ip().is_loopback()) {
+		let xff = req
+			.headers()
+			.get_all("x-forwarded-for")
+			.iter()
+			.next_back()
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.rsplit(',').next())
+			.and_then(|v| v.trim_ascii().parse::<IpAddr>().ok());
+		if let Some(ip) = xff {
+			return SocketAddr::new(ip, 0);
+		}
+	}
+	peer.unwrap_or(SocketAddr::from(([127, 0, 0, 1], 0)))
+}
+
+// RFC 8484 5.1, freshness lifetime no longer than the smallest TTL
+fn min_ttl(resp: &[u8]) -> u32 {
+	Message::from_vec(resp)
+		.ok()
+		.and_then(|m| {
+			m.answers()
+				.iter()
+				.chain(m.name_servers())
+				.map(|r| r.ttl())
+				.min()
+		})
+		.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_client_addr() {
+		let req = |peer: Option<&str>, xff: Option<&str>| {
+			let mut req = Request::new(());
+			if let Some(peer) = peer {
+				req.extensions_mut().insert(Peer(peer.parse().unwrap()));
+			}
+			if let Some(xff) = xff {
+				req.headers_mut()
+					.insert("x-forwarded-for", xff.parse().unwrap());
+			}
+			client_addr(&req)
+		};
+		assert_eq!(
+			req(Some("192.0.2.1:1234"), None),
+			"192.0.2.1:1234".parse().unwrap()
+		);
+		// only trusted from a local reverse proxy
+		assert_eq!(
+			req(Some("192.0.2.1:1234"), Some("198.51.100.1")),
+			"192.0.2.1:1234".parse().unwrap()
+		);
+		assert_eq!(
+			req(Some("127.0.0.1:1234"), Some("198.51.100.1, 127.0.0.1")),
+			"127.0.0.1:0".parse().unwrap()
+		);
+		// a spoofed leading entry is ignored
+		assert_eq!(
+			req(Some("127.0.0.1:1234"), Some("127.0.0.1, 198.51.100.1")),
+			"198.51.100.1:0".parse().unwrap()
+		);
+		assert_eq!(
+			req(None, Some("2001:db8::1")),
+			"[2001:db8::1]:0".parse().unwrap()
+		);
+	}
+}
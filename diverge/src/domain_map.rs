@@ -14,13 +14,18 @@ impl<T: Copy> DomainMap<T> {
 		self.0.insert(k.to_string(), v);
 	}
 
-	pub fn get(&self, mut k: &str) -> Option<T> {
+	pub fn get(&self, k: &str) -> Option<T> {
+		self.get_entry(k).map(|(_, v)| v)
+	}
+
+	// also returns the matched entry
+	pub fn get_entry<'a>(&self, mut k: &'a str) -> Option<(&'a str, T)> {
 		if k.ends_with('.') {
 			k = &k[0..k.len() - 1];
 		}
 		loop {
 			if let Some(v) = self.0.get(k) {
-				return Some(*v);
+				return Some((k, *v));
 			}
 			// "a.com" -> "com"
 			match k.find('.') {
@@ -55,5 +60,6 @@ mod tests {
 		] {
 			assert_eq!(m.get(t), e);
 		}
+		assert_eq!(m.get_entry("b.a.a."), Some(("a.a", ())));
 	}
 }
@@ -0,0 +1,167 @@
+// EDNS Client Subnet (RFC 7871), per upstream policy
+//	there's no cache, so honoring the scope prefix comes down to
+//	echoing it back to the client, which may have one
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use hickory_proto::{
+	op::Message,
+	rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption},
+};
+
+use crate::acl::parse_cidr;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Ecs {
+	// none sent upstream, the default
+	Strip,
+	// the client's, if it sent one
+	Pass,
+	// a configured subnet, whatever the client sent
+	Add(Subnet),
+}
+
+impl FromStr for Ecs {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"strip" => Ok(Ecs::Strip),
+			"pass" => Ok(Ecs::Pass),
+			_ => {
+				let (addr, len) = parse_cidr(s).map_err(|_| format!("invalid ecs: {}", s))?;
+				Ok(Ecs::Add(Subnet::new(addr, len as u8)))
+			}
+		}
+	}
+}
+
+impl Ecs {
+	// the option to send upstream, given the client's
+	pub fn query(&self, client: Option<Subnet>) -> Option<Subnet> {
+		match self {
+			Ecs::Strip => None,
+			Ecs::Pass => client.map(|c| Subnet { scope: 0, ..c }),
+			Ecs::Add(s) => Some(*s),
+		}
+	}
+}
+
+// hickory's ClientSubnet has no accessors
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub struct Subnet {
+	pub addr: IpAddr,
+	pub source: u8,
+	pub scope: u8,
+}
+
+impl Subnet {
+	// bits beyond the source prefix are cleared
+	pub fn new(addr: IpAddr, source: u8) -> Self {
+		let addr = match addr {
+			IpAddr::V4(a) => {
+				let mask = u32::MAX
+					.checked_shl(32 - source.min(32) as u32)
+					.unwrap_or(0);
+				IpAddr::from((u32::from(a) & mask).to_be_bytes())
+			}
+			IpAddr::V6(a) => {
+				let mask = u128::MAX
+					.checked_shl(128 - source.min(128) as u32)
+					.unwrap_or(0);
+				IpAddr::from((u128::from(a) & mask).to_be_bytes())
+			}
+		};
+		Self {
+			addr,
+			source,
+			scope: 0,
+		}
+	}
+
+	pub fn from_message(msg: &Message) -> Option<Self> {
+		match msg.extensions().as_ref()?.option(EdnsCode::Subnet)? {
+			EdnsOption::Subnet(s) => Self::from_option(s),
+			_ => None,
+		}
+	}
+
+	fn from_option(s: &ClientSubnet) -> Option<Self> {
+		// FAMILY, SOURCE PREFIX-LENGTH, SCOPE PREFIX-LENGTH, ADDRESS
+		let b = Vec::<u8>::try_from(s).ok()?;
+		let (family, source, scope, addr) = (u16::from_be_bytes([b[0], b[1]]), b[2], b[3], &b[4..]);
+		let addr = match family {
+			1 => {
+				let mut o = [0u8; 4];
+				o.get_mut(..addr.len())?.copy_from_slice(addr);
+				IpAddr::from(o)
+			}
+			2 => {
+				let mut o = [0u8; 16];
+				o.get_mut(..addr.len())?.copy_from_slice(addr);
+				IpAddr::from(o)
+			}
+			_ => return None,
+		};
+		Some(Self {
+			scope,
+			..Self::new(addr, source)
+		})
+	}
+
+	pub fn to_option(self) -> EdnsOption {
+		EdnsOption::Subnet(ClientSubnet::new(self.addr, self.source, self.scope))
+	}
+
+	// whether a response option answers this query option, RFC 7871 7.3
+	pub fn answered_by(&self, resp: &Subnet) -> bool {
+		self.addr == resp.addr && self.source == resp.source
+	}
+}
+
+impl fmt::Display for Subnet {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}/{}", self.addr, self.source, self.scope)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use hickory_proto::op::Edns;
+
+	#[test]
+	fn test() {
+		assert_eq!("strip".parse::<Ecs>().unwrap(), Ecs::Strip);
+		assert_eq!("Pass".parse::<Ecs>().unwrap(), Ecs::Pass);
+		let Ecs::Add(s) = "192.0.2.77/24".parse().unwrap() else {
+			panic!();
+		};
+		assert_eq!(s.addr, "192.0.2.0".parse::<IpAddr>().unwrap());
+		assert_eq!(s.source, 24);
+		assert!("192.0.2.0/33".parse::<Ecs>().is_err());
+
+		let client = Subnet {
+			scope: 16,
+			..Subnet::new("2001:db8:1234::1".parse().unwrap(), 48)
+		};
+		assert_eq!(Ecs::Strip.query(Some(client)), None);
+		assert_eq!(Ecs::Pass.query(None), None);
+		assert_eq!(Ecs::Pass.query(Some(client)).unwrap().scope, 0);
+		assert_eq!(Ecs::Add(s).query(Some(client)), Some(s));
+
+		// through the wire
+		let mut msg = Message::new();
+		let mut edns = Edns::new();
+		edns.options_mut().insert(client.to_option());
+		msg.set_edns(edns);
+		let msg = Message::from_vec(&msg.to_vec().unwrap()).unwrap();
+		let parsed = Subnet::from_message(&msg).unwrap();
+		assert_eq!(parsed, client);
+		assert!(Ecs::Pass.query(Some(client)).unwrap().answered_by(&parsed));
+		assert!(!s.answered_by(&parsed));
+		assert_eq!(Subnet::from_message(&Message::new()), None);
+	}
+}
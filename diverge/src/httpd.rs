@@ -0,0 +1,231 @@
+// a minimal HTTP server, for metrics, the admin API and DoH
+//	connections are handled on the local set, so handlers don't have to be Send
+//	HTTP/1.1 and HTTP/2, optionally over TLS
+
+use std::{
+	convert::Infallible,
+	fmt::{self, Display},
+	future::{self, Future},
+	net::SocketAddr,
+	os::unix::fs::FileTypeExt,
+	path::PathBuf,
+	pin::pin,
+	rc::Rc,
+	str::FromStr,
+};
+
+use http_body_util::Full;
+use hyper::{
+	body::{Bytes, Incoming},
+	header,
+	rt::Executor,
+	service::service_fn,
+	Request, Response, StatusCode,
+};
+use hyper_util::{rt::TokioIo, server::conn::auto};
+use log::*;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::UnixListener,
+	select,
+	sync::watch,
+	task::{self, JoinSet},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{bind::listen_tcp, systemd};
+
+pub type Body = Full<Bytes>;
+
+// peer address of the connection, in request extensions, not for unix sockets
+#[derive(Clone, Copy)]
+pub struct Peer(pub SocketAddr);
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, PartialEq)]
+pub enum Listen {
+	Tcp(SocketAddr),
+	// "unix:/path/to/socket"
+	Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+	type Err = std::net::AddrParseError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.strip_prefix("unix:") {
+			Some(path) => Ok(Listen::Unix(path.into())),
+			None => Ok(Listen::Tcp(s.parse()?)),
+		}
+	}
+}
+
+impl Display for Listen {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Listen::Tcp(addr) => write!(f, "{}", addr),
+			Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+		}
+	}
+}
+
+pub async fn serve<F, Fut>(listen: Listen, handler: F)
+where
+	F: Fn(Request<Incoming>) -> Fut + 'static,
+	Fut: Future<Output = Response<Body>> + 'static,
+{
+	serve_tls(listen, None, future::pending(), handler).await
+}
+
+// on stop, stops accepting and returns once connections have finished what they have
+pub async fn serve_tls<F, Fut>(
+	listen: Listen,
+	tls: Option<TlsAcceptor>,
+	stop: impl Future<Output = ()>,
+	handler: F,
+) where
+	F: Fn(Request<Incoming>) -> Fut + 'static,
+	Fut: Future<Output = Response<Body>> + 'static,
+{
+	let handler = Rc::new(handler);
+	let mut stop = pin!(stop);
+	// dropped to tell connections to shut down gracefully
+	let (stop_tx, stop_rx) = watch::channel(());
+	let mut conns = JoinSet::new();
+	match listen {
+		Listen::Tcp(addr) => {
+			let l = match listen_tcp(addr) {
+				Ok(l) => l,
+				Err(e) => {
+					error!("http bind {} error: {}", addr, e);
+					return;
+				}
+			};
+			loop {
+				let r = select! {
+					_ = &mut stop => break,
+					r = l.accept() => r,
+				};
+				match r {
+					Ok((s, addr)) => {
+						trace!("http connection from {}", addr);
+						let (tls, handler) = (tls.clone(), handler.clone());
+						conns.spawn_local(serve_conn(s, Some(addr), tls, handler, stop_rx.clone()));
+					}
+					Err(e) => error!("http accept error: {}", e),
+				}
+				while conns.try_join_next().is_some() {}
+			}
+		}
+		Listen::Unix(path) => {
+			let l = match systemd::take_unix(&path) {
+				Some(s) => UnixListener::from_std(s.into()),
+				None => {
+					// a stale socket from last run would fail the bind
+					//	but don't remove anything else that's there
+					if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+						let _ = std::fs::remove_file(&path);
+					}
+					UnixListener::bind(&path)
+				}
+			};
+			let l = match l {
+				Ok(l) => l,
+				Err(e) => {
+					error!("http bind {} error: {}", path.display(), e);
+					return;
+				}
+			};
+			loop {
+				let r = select! {
+					_ = &mut stop => break,
+					r = l.accept() => r,
+				};
+				match r {
+					Ok((s, _)) => {
+						trace!("http connection on {}", path.display());
+						let (tls, handler) = (tls.clone(), handler.clone());
+						conns.spawn_local(serve_conn(s, None, tls, handler, stop_rx.clone()));
+					}
+					Err(e) => error!("http accept error: {}", e),
+				}
+				while conns.try_join_next().is_some() {}
+			}
+		}
+	}
+	drop(stop_tx);
+	while conns.join_next().await.is_some() {}
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Io for T {}
+
+async fn serve_conn<S, F, Fut>(
+	s: S,
+	peer: Option<SocketAddr>,
+	tls: Option<TlsAcceptor>,
+	handler: Rc<F>,
+	mut stop: watch::Receiver<()>,
+) where
+	S: AsyncRead + AsyncWrite + Unpin + 'static,
+	F: Fn(Request<Incoming>) -> Fut + 'static,
+	Fut: Future<Output = Response<Body>> + 'static,
+{
+	let service = service_fn(|mut req: Request<Incoming>| {
+		if let Some(peer) = peer {
+			req.extensions_mut().insert(Peer(peer));
+		}
+		let f = handler(req);
+		async move { Ok::<_, Infallible>(f.await) }
+	});
+	let s: Box<dyn Io> = match tls {
+		Some(tls) => match tls.accept(s).await {
+			Ok(s) => Box::new(s),
+			Err(e) => {
+				debug!("tls handshake error: {}", e);
+				return;
+			}
+		},
+		None => Box::new(s),
+	};
+	let builder = auto::Builder::new(LocalExec);
+	let mut conn = pin!(builder.serve_connection(TokioIo::new(s), service));
+	let r = select! {
+		r = conn.as_mut() => r,
+		_ = stop.changed() => {
+			// finishes requests in flight, then closes
+			conn.as_mut().graceful_shutdown();
+			conn.await
+		}
+	};
+	if let Err(e) = r {
+		debug!("http connection error: {}", e);
+	}
+}
+
+// HTTP/2 streams are spawned on the local set too
+#[derive(Clone, Copy)]
+struct LocalExec;
+
+impl<F: Future + 'static> Executor<F> for LocalExec {
+	fn execute(&self, fut: F) {
+		task::spawn_local(fut);
+	}
+}
+
+pub fn json(v: &serde_json::Value) -> Response<Body> {
+	Response::builder()
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(Full::new(Bytes::from(v.to_string())))
+		.unwrap()
+}
+
+pub fn status(code: StatusCode) -> Response<Body> {
+	Response::builder()
+		.status(code)
+		.body(Full::new(Bytes::from(
+			code.canonical_reason().unwrap_or_default(),
+		)))
+		.unwrap()
+}
@@ -38,6 +38,20 @@ impl<T: Copy> IpMap<T> {
 			.map_or(self.default, |(_, _, v)| *v)
 	}
 
+	// also returns the matched prefix, if any
+	pub fn get_entry(&self, addr: IpAddr) -> (Option<(IpAddr, u32)>, T) {
+		match addr {
+			IpAddr::V4(addr) => self
+				.v4
+				.longest_match(addr)
+				.map_or((None, self.default), |(a, l, v)| (Some((a.into(), l)), *v)),
+			IpAddr::V6(addr) => self
+				.v6
+				.longest_match(addr)
+				.map_or((None, self.default), |(a, l, v)| (Some((a.into(), l)), *v)),
+		}
+	}
+
 	pub fn get(&self, addr: IpAddr) -> T {
 		match addr {
 			IpAddr::V4(addr) => self.get4(addr),
@@ -83,5 +97,10 @@ mod tests {
 		for (ip, expected) in tests.iter() {
 			assert_eq!(m.get(ip.parse().unwrap()), *expected);
 		}
+		assert_eq!(
+			m.get_entry("127.0.0.7".parse().unwrap()),
+			(Some(("127.0.0.0".parse().unwrap(), 24)), true)
+		);
+		assert_eq!(m.get_entry("127.0.1.7".parse().unwrap()), (None, false));
 	}
 }
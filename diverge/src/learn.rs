@@ -0,0 +1,181 @@
+// learns domain to upstream mappings from repeated ip map decisions
+//	into a generated domain list file per upstream, loaded like any other
+//	so it can be reviewed, edited or emptied, new entries apply on reload
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::{HashMap, HashSet},
+	fs::{self, OpenOptions},
+	io::{self, Write},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::*;
+use tokio::time::{Duration, Instant};
+
+// at most limit names are learned within this, across upstreams
+const LIMIT_WINDOW: Duration = Duration::from_secs(3600);
+// names being counted, beyond this the counting starts over
+const MAX_CANDIDATES: usize = 0x10000;
+
+const HEADER: &str = "# generated by diverge from ip map decisions, review and edit freely\n";
+
+pub struct Learn {
+	threshold: u32,
+	limit: u32,
+	// per upstream, None for those not learning
+	files: Vec<Option<String>>,
+	// name to (upstream, consecutive decisions for it)
+	candidates: RefCell<HashMap<String, (usize, u32)>>,
+	// already in the files
+	learned: RefCell<HashSet<String>>,
+	// (start, names learned since)
+	window: Cell<(Instant, u32)>,
+}
+
+impl Learn {
+	pub fn new(files: Vec<Option<String>>, threshold: u32, limit: u32) -> Self {
+		let mut learned = HashSet::new();
+		for f in files.iter().flatten() {
+			match fs::read_to_string(f) {
+				Ok(s) => learned.extend(
+					s.lines()
+						.map(|l| l.trim_ascii())
+						.filter(|l| !l.is_empty() && !l.starts_with('#'))
+						.map(normalize),
+				),
+				Err(e) if e.kind() == io::ErrorKind::NotFound => {
+					if let Err(e) = fs::write(f, HEADER) {
+						error!("failed to create {}: {}", f, e);
+					}
+				}
+				Err(e) => error!("failed to read {}: {}", f, e),
+			}
+			info!("learning domains to {}", f);
+		}
+		Self {
+			threshold,
+			limit,
+			files,
+			candidates: RefCell::new(HashMap::new()),
+			learned: RefCell::new(learned),
+			window: Cell::new((Instant::now(), 0)),
+		}
+	}
+
+	// the ip map chose this upstream for the name
+	pub fn record(&self, name: &str, upstream: usize) {
+		let name = normalize(name);
+		if self.learned.borrow().contains(&name) {
+			return;
+		}
+		let mut candidates = self.candidates.borrow_mut();
+		let Some(file) = &self.files[upstream] else {
+			candidates.remove(&name);
+			return;
+		};
+		if candidates.len() >= MAX_CANDIDATES && !candidates.contains_key(&name) {
+			candidates.clear();
+		}
+		let c = candidates.entry(name.clone()).or_insert((upstream, 0));
+		if c.0 != upstream {
+			*c = (upstream, 0);
+		}
+		c.1 += 1;
+		if c.1 < self.threshold {
+			return;
+		}
+
+		let now = Instant::now();
+		let (start, n) = match self.window.get() {
+			(start, _) if now - start >= LIMIT_WINDOW => (now, 0),
+			w => w,
+		};
+		if n >= self.limit {
+			debug!("learn limit reached, {} not learned", name);
+			self.window.set((start, n));
+			return;
+		}
+		self.window.set((start, n + 1));
+
+		let count = c.1;
+		candidates.remove(&name);
+		let ts = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		let entry = format!(
+			"# learned at {} after {} ip map decisions\n{}\n",
+			ts, count, name
+		);
+		match OpenOptions::new()
+			.append(true)
+			.create(true)
+			.open(file)
+			.and_then(|mut f| f.write_all(entry.as_bytes()))
+		{
+			Ok(_) => info!("learned {} to {}, applies on reload", name, file),
+			Err(e) => error!("failed to write {}: {}", file, e),
+		}
+		self.learned.borrow_mut().insert(name);
+	}
+}
+
+// as in domain lists
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test(start_paused = true)]
+	async fn test() {
+		let dir = std::env::temp_dir().join(format!("diverge-learn-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let file = dir.join("learned.lst").to_str().unwrap().to_string();
+		let entries = || -> Vec<String> {
+			fs::read_to_string(&file)
+				.unwrap()
+				.lines()
+				.filter(|l| !l.starts_with('#'))
+				.map(|l| l.to_string())
+				.collect()
+		};
+
+		let l = Learn::new(vec![None, Some(file.clone())], 2, 2);
+		assert_eq!(fs::read_to_string(&file).unwrap(), HEADER);
+		// upstream 0 doesn't learn
+		l.record("a.example.", 0);
+		l.record("a.example.", 0);
+		// switching upstream starts over
+		l.record("b.example.", 1);
+		l.record("b.example.", 0);
+		l.record("b.example.", 1);
+		assert!(entries().is_empty());
+		l.record("B.example.", 1);
+		l.record("c.example.", 1);
+		l.record("c.example.", 1);
+		// once only
+		l.record("c.example.", 1);
+		l.record("c.example.", 1);
+		assert_eq!(entries(), ["b.example", "c.example"]);
+
+		// limited
+		l.record("d.example.", 1);
+		l.record("d.example.", 1);
+		assert_eq!(entries().len(), 2);
+		tokio::time::advance(LIMIT_WINDOW).await;
+		l.record("d.example.", 1);
+		assert_eq!(entries().len(), 3);
+
+		// picked up on restart
+		let l = Learn::new(vec![None, Some(file.clone())], 1, 10);
+		l.record("c.example.", 1);
+		assert_eq!(entries().len(), 3);
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
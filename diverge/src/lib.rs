@@ -1,8 +1,23 @@
+pub mod acl;
+pub mod admin;
+pub mod bind;
 pub mod conf;
 pub mod diverge;
 pub mod dohc;
+pub mod dohd;
 pub mod domain_map;
+pub mod ecs;
+pub mod httpd;
 pub mod ip_map;
+pub mod learn;
+pub mod metrics;
+pub mod pool;
+pub mod querylog;
+pub mod ratelimit;
 pub mod resolver;
+pub mod stamp;
+pub mod systemd;
+pub mod tcpd;
+pub mod tls;
 pub mod udpd;
 pub mod utils;
@@ -1,15 +1,39 @@
+use std::rc::Rc;
+
+use futures::future::{join_all, select_all};
 use log::*;
-use tokio::task;
+use tokio::{
+	select,
+	signal::{
+		ctrl_c,
+		unix::{signal, SignalKind},
+	},
+	task,
+	time::{timeout, Duration},
+};
 
 use diverge::{
-	conf::{Conf, DivergeConf},
+	admin,
+	conf::{Conf, DivergeConf, Listener},
 	diverge::Diverge,
+	dohd::dohd,
+	metrics, systemd,
+	tcpd::tcpd,
+	tls,
 	udpd::udpd,
 };
 
+// for queries in flight on shutdown, docker stop waits 10s before SIGKILL
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// exit codes
+const EXIT_LISTENER_FAILED: i32 = 1;
+const EXIT_DRAIN_TIMEOUT: i32 = 2;
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+	systemd::init();
 
 	let conf_fn = if std::env::args().len() < 2 {
 		"diverge.conf".to_string()
@@ -20,9 +44,88 @@ async fn main() {
 	info!("read config from {}", &conf_fn);
 	let conf = DivergeConf::from_file(&conf_fn).unwrap();
 
-	let diverge = Diverge::from(&conf);
+	let diverge = Rc::new(Diverge::from(&conf));
 
 	let local = task::LocalSet::new();
-	local.run_until(udpd(conf.global.listen, diverge)).await;
-	local.await;
+	local.run_until(diverge.bootstrap()).await;
+	let mut servers = vec![
+		local.spawn_local(diverge.clone().probe()),
+		local.spawn_local(diverge.clone().rebootstrap()),
+		local.spawn_local(systemd::watchdog()),
+	];
+	let tls = |alpn: &[&[u8]]| match (&conf.global.tls_cert, &conf.global.tls_key) {
+		(Some(cert), Some(key)) => Some(tls::acceptor(cert, key, alpn)),
+		(None, None) => None,
+		_ => panic!("tls_cert and tls_key should be set together"),
+	};
+	// one task per listener, all sharing the same diverge
+	let mut listeners: Vec<_> = conf
+		.global
+		.listen
+		.iter()
+		.map(|l| match l {
+			Listener::Udp(addr) => local.spawn_local(udpd(*addr, diverge.clone())),
+			Listener::Tcp(addr) => local.spawn_local(tcpd(*addr, None, diverge.clone())),
+			Listener::Dot(addr) => {
+				// RFC 7858 doesn't require ALPN, but "dot" is registered
+				let Some(tls) = tls(&[b"dot"]) else {
+					panic!("dot requires tls_cert and tls_key");
+				};
+				local.spawn_local(tcpd(*addr, Some(tls), diverge.clone()))
+			}
+			Listener::Doh(listen) => {
+				let tls = tls(&[b"h2", b"http/1.1"]);
+				local.spawn_local(dohd(listen.clone(), tls, diverge.clone()))
+			}
+		})
+		.collect();
+	if let Some(listen) = conf.global.metrics.clone() {
+		servers.push(local.spawn_local(metrics::serve(listen, diverge.clone())));
+	}
+	if let Some(listen) = conf.global.admin.clone() {
+		servers.push(local.spawn_local(admin::serve(listen, diverge.clone())));
+	}
+	// let them all bind before telling systemd we're ready
+	local.run_until(task::yield_now()).await;
+	systemd::check_unused();
+	systemd::notify("READY=1");
+	// listeners only end on errors, like failing to bind, or on shutdown
+	let mut sigterm = signal(SignalKind::terminate()).unwrap();
+	let failed = local
+		.run_until(async {
+			select! {
+				_ = ctrl_c() => {
+					info!("SIGINT received, shutting down");
+					false
+				}
+				_ = sigterm.recv() => {
+					info!("SIGTERM received, shutting down");
+					false
+				}
+				(_, i, _) = select_all(listeners.iter_mut()) => {
+					error!("listener {} failed, exiting", conf.global.listen[i]);
+					true
+				}
+			}
+		})
+		.await;
+	systemd::notify("STOPPING=1");
+	let mut code = 0;
+	if failed {
+		code = EXIT_LISTENER_FAILED;
+	} else {
+		diverge.shutdown();
+		let drained = local.run_until(timeout(DRAIN_TIMEOUT, join_all(listeners.iter_mut())));
+		if drained.await.is_err() {
+			warn!("queries still in flight after {:?}, dropped", DRAIN_TIMEOUT);
+			code = EXIT_DRAIN_TIMEOUT;
+		}
+	}
+	for s in servers.into_iter().chain(listeners) {
+		s.abort();
+	}
+	// not awaited, idle connections of metrics and admin could hold it
+	drop(local);
+	diverge.flush();
+	std::process::exit(code);
 }
@@ -0,0 +1,367 @@
+// prometheus metrics, in text exposition format
+//	everything runs on a single thread, so plain Cell/RefCell will do
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::BTreeMap,
+	fmt::Write,
+	rc::Rc,
+	time::Duration,
+};
+
+use hickory_proto::{
+	op::ResponseCode,
+	rr::{DNSClass, RecordType},
+};
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Method, Response, StatusCode};
+use log::*;
+
+use crate::{
+	diverge::Diverge,
+	httpd::{self, Listen},
+	utils::rcode_str,
+};
+
+// in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 2.5, 5.0];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Decision {
+	DomainMap,
+	IpMap,
+	Fallback,
+	// a CNAME target matched the domain map
+	Cname,
+}
+
+impl Decision {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Decision::DomainMap => "domain_map",
+			Decision::IpMap => "ip_map",
+			Decision::Fallback => "fallback",
+			Decision::Cname => "cname",
+		}
+	}
+}
+
+struct Histogram {
+	buckets: [Cell<u64>; LATENCY_BUCKETS.len()],
+	sum: Cell<f64>,
+	count: Cell<u64>,
+}
+
+impl Histogram {
+	fn new() -> Self {
+		Self {
+			buckets: Default::default(),
+			sum: Cell::new(0.),
+			count: Cell::new(0),
+		}
+	}
+
+	fn observe(&self, v: f64) {
+		for (b, le) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+			if v <= le {
+				inc(b, 1);
+			}
+		}
+		self.sum.set(self.sum.get() + v);
+		inc(&self.count, 1);
+	}
+}
+
+struct UpstreamMetrics {
+	name: String,
+	requests: Cell<u64>,
+	errors: Cell<u64>,
+	timeouts: Cell<u64>,
+	pruned: Cell<u64>,
+	down: Cell<bool>,
+	latency: Histogram,
+}
+
+pub struct Metrics {
+	queries: RefCell<BTreeMap<(u16, u16), u64>>,
+	responses: RefCell<BTreeMap<u16, u64>>,
+	decisions: RefCell<BTreeMap<(usize, Decision), u64>>,
+	denied: RefCell<BTreeMap<&'static str, u64>>,
+	upstreams: Vec<UpstreamMetrics>,
+}
+
+impl Metrics {
+	pub fn new<'a>(upstreams: impl IntoIterator<Item = &'a str>) -> Self {
+		Self {
+			queries: RefCell::new(BTreeMap::new()),
+			responses: RefCell::new(BTreeMap::new()),
+			decisions: RefCell::new(BTreeMap::new()),
+			denied: RefCell::new(BTreeMap::new()),
+			upstreams: upstreams
+				.into_iter()
+				.map(|name| UpstreamMetrics {
+					name: name.to_string(),
+					requests: Cell::new(0),
+					errors: Cell::new(0),
+					timeouts: Cell::new(0),
+					pruned: Cell::new(0),
+					down: Cell::new(false),
+					latency: Histogram::new(),
+				})
+				.collect(),
+		}
+	}
+
+	pub fn query(&self, class: DNSClass, rtype: RecordType) {
+		*self
+			.queries
+			.borrow_mut()
+			.entry((class.into(), rtype.into()))
+			.or_default() += 1;
+	}
+
+	pub fn response(&self, rcode: ResponseCode) {
+		*self.responses.borrow_mut().entry(rcode.into()).or_default() += 1;
+	}
+
+	pub fn decision(&self, upstream: usize, decision: Decision) {
+		*self
+			.decisions
+			.borrow_mut()
+			.entry((upstream, decision))
+			.or_default() += 1;
+	}
+
+	// queries refused or dropped before resolving, by reason
+	pub fn denied(&self, reason: &'static str) {
+		*self.denied.borrow_mut().entry(reason).or_default() += 1;
+	}
+
+	pub fn request(&self, upstream: usize) {
+		inc(&self.upstreams[upstream].requests, 1);
+	}
+
+	// an answer, including "no records found", which is still an answer
+	pub fn answered(&self, upstream: usize, elapsed: Duration) {
+		self.upstreams[upstream]
+			.latency
+			.observe(elapsed.as_secs_f64());
+	}
+
+	pub fn error(&self, upstream: usize, elapsed: Duration) {
+		let u = &self.upstreams[upstream];
+		inc(&u.errors, 1);
+		u.latency.observe(elapsed.as_secs_f64());
+	}
+
+	pub fn timeout(&self, upstream: usize) {
+		inc(&self.upstreams[upstream].timeouts, 1);
+	}
+
+	pub fn pruned(&self, upstream: usize, c: usize) {
+		inc(&self.upstreams[upstream].pruned, c as u64);
+	}
+
+	pub fn down(&self, upstream: usize, down: bool) {
+		self.upstreams[upstream].down.set(down);
+	}
+
+	// requests, errors, timeouts
+	pub fn upstream_counters(&self, upstream: usize) -> (u64, u64, u64) {
+		let u = &self.upstreams[upstream];
+		(u.requests.get(), u.errors.get(), u.timeouts.get())
+	}
+
+	pub fn render(&self) -> String {
+		let mut s = String::with_capacity(0x1000);
+
+		header(
+			&mut s,
+			"diverge_queries_total",
+			"counter",
+			"queries received",
+		);
+		for ((class, rtype), c) in self.queries.borrow().iter() {
+			let _ = writeln!(
+				s,
+				"diverge_queries_total{{class=\"{}\",type=\"{}\"}} {}",
+				DNSClass::from(*class),
+				RecordType::from(*rtype),
+				c
+			);
+		}
+
+		header(
+			&mut s,
+			"diverge_responses_total",
+			"counter",
+			"responses sent",
+		);
+		for (rcode, c) in self.responses.borrow().iter() {
+			let _ = writeln!(
+				s,
+				"diverge_responses_total{{rcode=\"{}\"}} {}",
+				rcode_str(<ResponseCode as From<u16>>::from(*rcode)),
+				c
+			);
+		}
+
+		header(
+			&mut s,
+			"diverge_decisions_total",
+			"counter",
+			"upstream chosen for a query, and how",
+		);
+		for ((i, d), c) in self.decisions.borrow().iter() {
+			let _ = writeln!(
+				s,
+				"diverge_decisions_total{{upstream=\"{}\",method=\"{}\"}} {}",
+				self.upstreams[*i].name,
+				d.as_str(),
+				c
+			);
+		}
+
+		header(
+			&mut s,
+			"diverge_denied_total",
+			"counter",
+			"queries refused or dropped before resolving",
+		);
+		for (reason, c) in self.denied.borrow().iter() {
+			let _ = writeln!(s, "diverge_denied_total{{reason=\"{}\"}} {}", reason, c);
+		}
+
+		for (name, help, f) in [
+			(
+				"diverge_upstream_requests_total",
+				"requests sent to upstream",
+				(|u| &u.requests) as fn(&UpstreamMetrics) -> &Cell<u64>,
+			),
+			(
+				"diverge_upstream_errors_total",
+				"upstream errors, excluding no records found",
+				|u| &u.errors,
+			),
+			(
+				"diverge_upstream_timeouts_total",
+				"upstream lookups timed out",
+				|u| &u.timeouts,
+			),
+			(
+				"diverge_pruned_records_total",
+				"A/AAAA records pruned from upstream answers",
+				|u| &u.pruned,
+			),
+		] {
+			header(&mut s, name, "counter", help);
+			for u in self.upstreams.iter() {
+				let _ = writeln!(s, "{}{{upstream=\"{}\"}} {}", name, u.name, f(u).get());
+			}
+		}
+
+		let name = "diverge_upstream_down";
+		header(&mut s, name, "gauge", "circuit breaker opened for upstream");
+		for u in self.upstreams.iter() {
+			let _ = writeln!(
+				s,
+				"{}{{upstream=\"{}\"}} {}",
+				name,
+				u.name,
+				u.down.get() as u8
+			);
+		}
+
+		let name = "diverge_upstream_latency_seconds";
+		header(&mut s, name, "histogram", "upstream response latency");
+		for u in self.upstreams.iter() {
+			let h = &u.latency;
+			for (b, le) in h.buckets.iter().zip(LATENCY_BUCKETS) {
+				let _ = writeln!(
+					s,
+					"{}_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+					name,
+					u.name,
+					le,
+					b.get()
+				);
+			}
+			let _ = writeln!(
+				s,
+				"{}_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+				name,
+				u.name,
+				h.count.get()
+			);
+			let _ = writeln!(s, "{}_sum{{upstream=\"{}\"}} {}", name, u.name, h.sum.get());
+			let _ = writeln!(
+				s,
+				"{}_count{{upstream=\"{}\"}} {}",
+				name,
+				u.name,
+				h.count.get()
+			);
+		}
+		s
+	}
+}
+
+fn inc(c: &Cell<u64>, v: u64) {
+	c.set(c.get() + v);
+}
+
+fn header(s: &mut String, name: &str, t: &str, help: &str) {
+	let _ = writeln!(s, "# HELP {} {}", name, help);
+	let _ = writeln!(s, "# TYPE {} {}", name, t);
+}
+
+pub async fn serve(listen: Listen, diverge: Rc<Diverge>) {
+	info!("serving metrics on http://{}/metrics", listen);
+	httpd::serve(listen, move |req| {
+		let diverge = diverge.clone();
+		async move {
+			if req.method() != Method::GET || req.uri().path() != "/metrics" {
+				return httpd::status(StatusCode::NOT_FOUND);
+			}
+			Response::builder()
+				.header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+				.body(Full::new(Bytes::from(diverge.metrics().render())))
+				.unwrap()
+		}
+	})
+	.await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let m = Metrics::new(["0", "X"]);
+		m.query(DNSClass::IN, RecordType::AAAA);
+		m.response(ResponseCode::NoError);
+		m.decision(1, Decision::IpMap);
+		m.request(1);
+		m.answered(1, Duration::from_millis(30));
+		m.timeout(0);
+		m.pruned(0, 3);
+		m.denied("acl");
+
+		let s = m.render();
+		for l in [
+			"diverge_queries_total{class=\"IN\",type=\"AAAA\"} 1",
+			"diverge_responses_total{rcode=\"NOERROR\"} 1",
+			"diverge_decisions_total{upstream=\"X\",method=\"ip_map\"} 1",
+			"diverge_denied_total{reason=\"acl\"} 1",
+			"diverge_upstream_requests_total{upstream=\"X\"} 1",
+			"diverge_upstream_timeouts_total{upstream=\"0\"} 1",
+			"diverge_pruned_records_total{upstream=\"0\"} 3",
+			"diverge_upstream_latency_seconds_bucket{upstream=\"X\",le=\"0.025\"} 0",
+			"diverge_upstream_latency_seconds_bucket{upstream=\"X\",le=\"0.05\"} 1",
+			"diverge_upstream_latency_seconds_count{upstream=\"X\"} 1",
+		] {
+			assert!(s.lines().any(|e| e == l), "missing: {}", l);
+		}
+	}
+}
@@ -0,0 +1,249 @@
+// explicit selection across the addresses of one upstream
+//	instead of hickory's internal ordering
+
+use std::{cell::Cell, future::Future, net::IpAddr, str::FromStr};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use log::*;
+use tokio::time::{timeout, Duration, Instant};
+
+// leaves room for a failover within the upstream lookup timeout
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+// addresses that failed within this are tried last
+const FAILED_BACKOFF: Duration = Duration::from_secs(30);
+// weight of the latest sample in the moving average RTT
+const RTT_ALPHA: f64 = 0.3;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+	// failover in order
+	Sequential,
+	RoundRobin,
+	// lowest moving average RTT
+	Fastest,
+	// all at once, the first answer wins
+	Race,
+}
+
+impl FromStr for Strategy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"sequential" => Ok(Strategy::Sequential),
+			"round_robin" => Ok(Strategy::RoundRobin),
+			"fastest" => Ok(Strategy::Fastest),
+			"race" => Ok(Strategy::Race),
+			_ => Err(format!("unknown strategy: {}", s)),
+		}
+	}
+}
+
+struct Member<T> {
+	addr: IpAddr,
+	inner: T,
+	// seconds
+	rtt: Cell<Option<f64>>,
+	failed: Cell<Option<Instant>>,
+}
+
+impl<T> Member<T> {
+	fn succeeded(&self, elapsed: Duration) {
+		self.sample(elapsed);
+		self.failed.set(None);
+	}
+
+	fn failed(&self) {
+		self.sample(ATTEMPT_TIMEOUT);
+		self.failed.set(Some(Instant::now()));
+	}
+
+	fn sample(&self, elapsed: Duration) {
+		let s = elapsed.as_secs_f64();
+		self.rtt.set(Some(match self.rtt.get() {
+			Some(rtt) => rtt * (1. - RTT_ALPHA) + s * RTT_ALPHA,
+			None => s,
+		}));
+	}
+
+	fn recently_failed(&self) -> bool {
+		self.failed
+			.get()
+			.is_some_and(|t| t.elapsed() < FAILED_BACKOFF)
+	}
+}
+
+pub struct Pool<T> {
+	strategy: Strategy,
+	members: Vec<Member<T>>,
+	next: Cell<usize>,
+}
+
+impl<T> Pool<T> {
+	pub fn new(strategy: Strategy, members: impl IntoIterator<Item = (IpAddr, T)>) -> Self {
+		let members: Vec<_> = members
+			.into_iter()
+			.map(|(addr, inner)| Member {
+				addr,
+				inner,
+				rtt: Cell::new(None),
+				failed: Cell::new(None),
+			})
+			.collect();
+		assert!(!members.is_empty());
+		Self {
+			strategy,
+			members,
+			next: Cell::new(0),
+		}
+	}
+
+	// the order to try members in, for this query
+	fn order(&self) -> Vec<usize> {
+		let n = self.members.len();
+		match self.strategy {
+			Strategy::Sequential | Strategy::Race => {
+				let (mut ok, failed): (Vec<_>, Vec<_>) =
+					(0..n).partition(|&i| !self.members[i].recently_failed());
+				ok.extend(failed);
+				ok
+			}
+			Strategy::RoundRobin => {
+				let s = self.next.get();
+				self.next.set((s + 1) % n);
+				(0..n).map(|i| (s + i) % n).collect()
+			}
+			Strategy::Fastest => {
+				let mut v: Vec<_> = (0..n).collect();
+				// unmeasured ones first, so they get measured
+				v.sort_by(|&a, &b| {
+					let rtt = |i: usize| self.members[i].rtt.get().unwrap_or(0.);
+					rtt(a).total_cmp(&rtt(b))
+				});
+				v
+			}
+		}
+	}
+
+	pub async fn run<'a, F, Fut, R>(&'a self, f: F) -> Result<R, ResolveError>
+	where
+		F: Fn(&'a T) -> Fut,
+		Fut: Future<Output = Result<R, ResolveError>>,
+	{
+		let order = self.order();
+		let mut last = None;
+		if self.strategy == Strategy::Race {
+			let mut futs: FuturesUnordered<_> =
+				order.into_iter().map(|i| self.attempt(i, &f)).collect();
+			while let Some(r) = futs.next().await {
+				if answered(&r) {
+					return r;
+				}
+				last = Some(r);
+			}
+		} else {
+			for i in order {
+				let r = self.attempt(i, &f).await;
+				if answered(&r) {
+					return r;
+				}
+				last = Some(r);
+			}
+		}
+		last.unwrap()
+	}
+
+	async fn attempt<'a, F, Fut, R>(&'a self, i: usize, f: &F) -> Result<R, ResolveError>
+	where
+		F: Fn(&'a T) -> Fut,
+		Fut: Future<Output = Result<R, ResolveError>>,
+	{
+		let m = &self.members[i];
+		let t0 = Instant::now();
+		let r = match timeout(ATTEMPT_TIMEOUT, f(&m.inner)).await {
+			Ok(r) => r,
+			Err(_) => Err(ResolveErrorKind::Timeout.into()),
+		};
+		if answered(&r) {
+			m.succeeded(t0.elapsed());
+		} else if let Err(e) = &r {
+			debug!("{} failed: {}", m.addr, e);
+			m.failed();
+		}
+		r
+	}
+}
+
+// NXDOMAIN and NODATA are answers too, no point asking another address
+fn answered<R>(r: &Result<R, ResolveError>) -> bool {
+	match r {
+		Ok(_) => true,
+		Err(e) => matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// members are (delay in ms, succeed)
+	fn pool(strategy: Strategy, members: &[(u64, bool)]) -> Pool<(u64, bool)> {
+		Pool::new(
+			strategy,
+			members
+				.iter()
+				.enumerate()
+				.map(|(i, m)| (IpAddr::from([192, 0, 2, i as u8]), *m)),
+		)
+	}
+
+	async fn run(p: &Pool<(u64, bool)>) -> Result<u64, ResolveError> {
+		p.run(|&(delay, ok)| async move {
+			tokio::time::sleep(Duration::from_millis(delay)).await;
+			if ok {
+				Ok(delay)
+			} else {
+				Err(ResolveError::from("failed"))
+			}
+		})
+		.await
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_sequential() {
+		let p = pool(Strategy::Sequential, &[(10, false), (20, true), (30, true)]);
+		assert_eq!(run(&p).await.unwrap(), 20);
+		// the failed one is tried last now
+		assert_eq!(p.order(), vec![1, 2, 0]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_round_robin() {
+		let p = pool(Strategy::RoundRobin, &[(10, true), (20, true), (30, true)]);
+		assert_eq!(run(&p).await.unwrap(), 10);
+		assert_eq!(run(&p).await.unwrap(), 20);
+		assert_eq!(run(&p).await.unwrap(), 30);
+		assert_eq!(run(&p).await.unwrap(), 10);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_fastest() {
+		let p = pool(Strategy::Fastest, &[(30, true), (10, true), (2000, true)]);
+		// measure them all
+		for _ in 0..3 {
+			run(&p).await.ok();
+		}
+		assert_eq!(run(&p).await.unwrap(), 10);
+		assert_eq!(p.order(), vec![1, 0, 2]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_race() {
+		let p = pool(Strategy::Race, &[(30, true), (5, false), (20, true)]);
+		assert_eq!(run(&p).await.unwrap(), 20);
+		let p = pool(Strategy::Race, &[(30, false), (5, false)]);
+		assert!(run(&p).await.is_err());
+	}
+}
@@ -0,0 +1,329 @@
+// structured query log, one JSON object per line
+//	for science, and for tuning IP lists
+
+use std::{
+	cell::{Cell, RefCell},
+	fs::{self, File, OpenOptions},
+	io::{self, LineWriter, Write},
+	net::{IpAddr, SocketAddr},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hickory_proto::{op::ResponseCode, rr::RecordType};
+use log::*;
+use serde_json::{json, Value};
+
+use crate::{ecs::Subnet, metrics::Decision, utils::rcode_str};
+
+// per upstream part of a query
+pub struct UpstreamTrace {
+	pub upstream: usize,
+	pub outcome: &'static str,
+	pub elapsed: Option<Duration>,
+	pub kept: Vec<IpAddr>,
+	pub pruned: Vec<IpAddr>,
+	// of the ECS option in the answer, if it's for the client's
+	pub scope: Option<u8>,
+}
+
+// collected along the way of a query
+//	Diverge::query runs concurrent lookups, hence the interior mutability
+pub struct Trace {
+	client: SocketAddr,
+	// the client's ECS option
+	ecs: Option<Subnet>,
+	question: RefCell<Option<(String, RecordType)>>,
+	decision: Cell<Option<(usize, Decision)>>,
+	upstreams: RefCell<Vec<UpstreamTrace>>,
+	// an admin explain, not a client query
+	explain: bool,
+}
+
+impl Trace {
+	pub fn new(client: SocketAddr, ecs: Option<Subnet>) -> Self {
+		Self {
+			client,
+			ecs,
+			question: RefCell::new(None),
+			decision: Cell::new(None),
+			upstreams: RefCell::new(Vec::new()),
+			explain: false,
+		}
+	}
+
+	// decisions aren't counted or learned from
+	pub fn explain(client: SocketAddr) -> Self {
+		Self {
+			explain: true,
+			..Self::new(client, None)
+		}
+	}
+
+	pub fn is_explain(&self) -> bool {
+		self.explain
+	}
+
+	pub fn ecs(&self) -> Option<Subnet> {
+		self.ecs
+	}
+
+	pub fn question(&self, name: String, rtype: RecordType) {
+		*self.question.borrow_mut() = Some((name, rtype));
+	}
+
+	pub fn decision(&self, upstream: usize, decision: Decision) {
+		self.decision.set(Some((upstream, decision)));
+	}
+
+	pub fn outcome(&self, upstream: usize, outcome: &'static str, elapsed: Option<Duration>) {
+		self.upstreams.borrow_mut().push(UpstreamTrace {
+			upstream,
+			outcome,
+			elapsed,
+			kept: Vec::new(),
+			pruned: Vec::new(),
+			scope: None,
+		});
+	}
+
+	pub fn scope(&self, upstream: usize, scope: u8) {
+		if let Some(u) = self
+			.upstreams
+			.borrow_mut()
+			.iter_mut()
+			.rev()
+			.find(|u| u.upstream == upstream)
+		{
+			u.scope = Some(scope);
+		}
+	}
+
+	// the ECS option to answer the client with, scoped by the chosen upstream
+	//	0 if it wasn't sent the client's, the answer is good for any client then
+	pub fn ecs_response(&self) -> Option<Subnet> {
+		let ecs = self.ecs?;
+		let scope = self.decision.get().and_then(|(i, _)| {
+			let upstreams = self.upstreams.borrow();
+			upstreams.iter().rev().find(|u| u.upstream == i)?.scope
+		});
+		Some(Subnet {
+			scope: scope.unwrap_or(0),
+			..ecs
+		})
+	}
+
+	pub fn prune(&self, upstream: usize, kept: bool, addr: IpAddr) {
+		let mut upstreams = self.upstreams.borrow_mut();
+		let Some(u) = upstreams.iter_mut().rev().find(|u| u.upstream == upstream) else {
+			return;
+		};
+		if kept {
+			u.kept.push(addr);
+		} else {
+			u.pruned.push(addr);
+		}
+	}
+
+	pub fn to_json(&self, names: &[String]) -> Value {
+		let (name, rtype) = match self.question.borrow().as_ref() {
+			Some((name, rtype)) => (Value::from(name.as_str()), Value::from(rtype.to_string())),
+			None => (Value::Null, Value::Null),
+		};
+		let (decision, upstream) = match self.decision.get() {
+			Some((i, d)) => (Value::from(d.as_str()), Value::from(names[i].as_str())),
+			None => (Value::Null, Value::Null),
+		};
+		let upstreams: Vec<Value> = self
+			.upstreams
+			.borrow()
+			.iter()
+			.map(|u| {
+				json!({
+					"upstream": names[u.upstream],
+					"outcome": u.outcome,
+					"latency_ms": u.elapsed.map(|e| e.as_secs_f64() * 1000.),
+					"kept": u.kept,
+					"pruned": u.pruned,
+					"ecs_scope": u.scope,
+				})
+			})
+			.collect();
+		json!({
+			"client": self.client.to_string(),
+			"ecs": self.ecs.map(|e| e.to_string()),
+			"name": name,
+			"type": rtype,
+			"decision": decision,
+			"upstream": upstream,
+			"upstreams": upstreams,
+		})
+	}
+}
+
+pub struct QueryLog {
+	path: PathBuf,
+	max_size: u64,
+	keep: usize,
+	names: Vec<String>,
+	w: RefCell<Option<(LineWriter<File>, u64)>>,
+}
+
+impl QueryLog {
+	pub fn new(path: impl AsRef<Path>, max_size: u64, keep: usize, names: Vec<String>) -> Self {
+		let path = path.as_ref().to_path_buf();
+		let w = RefCell::new(open(&path));
+		info!("query log to {}", path.display());
+		Self {
+			path,
+			max_size,
+			keep,
+			names,
+			w,
+		}
+	}
+
+	pub fn write(&self, trace: &Trace, rcode: ResponseCode) {
+		let mut v = trace.to_json(&self.names);
+		v["ts"] = json!(SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs_f64());
+		v["rcode"] = json!(rcode_str(rcode));
+		let mut l = v.to_string();
+		l.push('\n');
+
+		let mut w = self.w.borrow_mut();
+		if let Some((_, size)) = w.as_ref() {
+			if *size > 0 && *size + l.len() as u64 > self.max_size {
+				*w = None;
+				self.rotate();
+				*w = open(&self.path);
+			}
+		}
+		let Some((f, size)) = w.as_mut() else {
+			return;
+		};
+		match f.write_all(l.as_bytes()) {
+			Ok(_) => *size += l.len() as u64,
+			Err(e) => error!("query log write error: {}", e),
+		}
+	}
+
+	pub fn flush(&self) {
+		if let Some((f, _)) = self.w.borrow_mut().as_mut() {
+			if let Err(e) = f.flush() {
+				error!("query log flush error: {}", e);
+			}
+		}
+	}
+
+	// query.log -> query.log.1 -> query.log.2 ...
+	fn rotate(&self) {
+		debug!("rotating query log {}", self.path.display());
+		if self.keep == 0 {
+			if let Err(e) = fs::remove_file(&self.path) {
+				warn!("failed to remove {}: {}", self.path.display(), e);
+			}
+			return;
+		}
+		for i in (1..self.keep).rev() {
+			let _ = fs::rename(rotated(&self.path, i), rotated(&self.path, i + 1));
+		}
+		if let Err(e) = fs::rename(&self.path, rotated(&self.path, 1)) {
+			warn!("failed to rotate {}: {}", self.path.display(), e);
+		}
+	}
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+	let mut p = path.as_os_str().to_owned();
+	p.push(format!(".{}", i));
+	p.into()
+}
+
+fn open(path: &Path) -> Option<(LineWriter<File>, u64)> {
+	let f = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.and_then(|f| Ok((f.metadata()?.len(), f)));
+	match f {
+		Ok((size, f)) => Some((LineWriter::new(f), size)),
+		Err(e) => {
+			error!("failed to open query log {}: {}", path.display(), e);
+			None
+		}
+	}
+}
+
+// 1048576, 1024K, 1M, 1G
+pub fn parse_size(s: &str) -> io::Result<u64> {
+	let s = s.trim_ascii();
+	let (n, m) = match s.char_indices().last() {
+		Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+		Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+		Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+		_ => (s, 1),
+	};
+	let n = n
+		.trim_ascii()
+		.parse::<u64>()
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+	n.checked_mul(m)
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "size too large"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_size() {
+		assert_eq!(parse_size("1024").unwrap(), 1024);
+		assert_eq!(parse_size("4K").unwrap(), 4096);
+		assert_eq!(parse_size("16M").unwrap(), 16 << 20);
+		assert_eq!(parse_size("1 g").unwrap(), 1 << 30);
+		assert!(parse_size("M").is_err());
+		assert!(parse_size("99999999999G").is_err());
+	}
+
+	#[test]
+	fn test_rotate() {
+		let dir = std::env::temp_dir().join(format!("diverge-querylog-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("query.log");
+
+		let log = QueryLog::new(&path, 400, 2, vec!["0".to_string(), "X".to_string()]);
+		let ecs = Subnet::new("192.0.2.0".parse().unwrap(), 24);
+		let trace = Trace::new("127.0.0.1:5353".parse().unwrap(), Some(ecs));
+		trace.question("www.example.com.".to_string(), RecordType::A);
+		trace.outcome(0, "records", Some(Duration::from_millis(12)));
+		trace.scope(0, 20);
+		trace.prune(0, true, "192.0.2.1".parse().unwrap());
+		trace.prune(0, false, "198.51.100.1".parse().unwrap());
+		trace.decision(0, Decision::IpMap);
+		assert_eq!(trace.ecs_response().unwrap().scope, 20);
+		for _ in 0..10 {
+			log.write(&trace, ResponseCode::NoError);
+		}
+		log.flush();
+
+		let l = fs::read_to_string(&path).unwrap();
+		let v: Value = serde_json::from_str(l.lines().next().unwrap()).unwrap();
+		assert_eq!(v["client"], "127.0.0.1:5353");
+		assert_eq!(v["decision"], "ip_map");
+		assert_eq!(v["upstream"], "0");
+		assert_eq!(v["upstreams"][0]["kept"][0], "192.0.2.1");
+		assert_eq!(v["upstreams"][0]["pruned"][0], "198.51.100.1");
+		assert_eq!(v["ecs"], "192.0.2.0/24/0");
+		assert_eq!(v["upstreams"][0]["ecs_scope"], 20);
+		assert!(fs::metadata(&path).unwrap().len() <= 400);
+		assert!(rotated(&path, 1).exists());
+		assert!(rotated(&path, 2).exists());
+		assert!(!rotated(&path, 3).exists());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,159 @@
+// per client prefix token bucket rate limiting
+
+use std::{
+	cell::{Cell, RefCell},
+	collections::HashMap,
+	net::IpAddr,
+	str::FromStr,
+};
+
+use log::*;
+use tokio::time::{Duration, Instant};
+
+// full buckets are the same as no bucket, drop them every so often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// against floods from spoofed sources, swept early at this, and cleared if that's not enough
+const MAX_BUCKETS: usize = 0x10000;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum LimitAction {
+	Refuse,
+	// TC set, so the client retries over TCP, which is refused when over the limit
+	Truncate,
+}
+
+impl FromStr for LimitAction {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"refuse" => Ok(LimitAction::Refuse),
+			"truncate" => Ok(LimitAction::Truncate),
+			_ => Err(format!("invalid rate limit action: {}", s)),
+		}
+	}
+}
+
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+pub struct RateLimit {
+	qps: f64,
+	burst: f64,
+	prefix_v4: u8,
+	prefix_v6: u8,
+	pub action: LimitAction,
+	buckets: RefCell<HashMap<IpAddr, Bucket>>,
+	swept: Cell<Instant>,
+}
+
+impl RateLimit {
+	pub fn new(qps: f64, burst: f64, prefix_v4: u8, prefix_v6: u8, action: LimitAction) -> Self {
+		info!(
+			"rate limit {} qps, burst {}, per /{} and /{}",
+			qps, burst, prefix_v4, prefix_v6
+		);
+		Self {
+			qps,
+			burst,
+			prefix_v4,
+			prefix_v6,
+			action,
+			buckets: RefCell::new(HashMap::new()),
+			swept: Cell::new(Instant::now()),
+		}
+	}
+
+	// takes a token, false if there's none
+	pub fn check(&self, client: IpAddr) -> bool {
+		let now = Instant::now();
+		let mut buckets = self.buckets.borrow_mut();
+		let prefix = self.prefix(client);
+		let full = buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&prefix);
+		if full || now - self.swept.get() > SWEEP_INTERVAL {
+			buckets.retain(|_, b| self.refill(b, now) < self.burst);
+			self.swept.set(now);
+			if buckets.len() >= MAX_BUCKETS {
+				warn!("rate limit buckets full, cleared");
+				buckets.clear();
+			}
+		}
+		let b = buckets.entry(prefix).or_insert_with(|| Bucket {
+			tokens: self.burst,
+			last: now,
+		});
+		self.refill(b, now);
+		if b.tokens < 1. {
+			return false;
+		}
+		b.tokens -= 1.;
+		true
+	}
+
+	fn refill(&self, b: &mut Bucket, now: Instant) -> f64 {
+		b.tokens = (b.tokens + (now - b.last).as_secs_f64() * self.qps).min(self.burst);
+		b.last = now;
+		b.tokens
+	}
+
+	fn prefix(&self, client: IpAddr) -> IpAddr {
+		match client.to_canonical() {
+			IpAddr::V4(a) => {
+				let mask = u32::MAX
+					.checked_shl(32 - self.prefix_v4 as u32)
+					.unwrap_or(0);
+				IpAddr::from((u32::from(a) & mask).to_be_bytes())
+			}
+			IpAddr::V6(a) => {
+				let mask = u128::MAX
+					.checked_shl(128 - self.prefix_v6 as u32)
+					.unwrap_or(0);
+				IpAddr::from((u128::from(a) & mask).to_be_bytes())
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test(start_paused = true)]
+	async fn test() {
+		let r = RateLimit::new(10., 3., 24, 56, LimitAction::Refuse);
+		let a: IpAddr = "192.0.2.1".parse().unwrap();
+		let b: IpAddr = "192.0.2.2".parse().unwrap();
+		let c: IpAddr = "198.51.100.1".parse().unwrap();
+		// burst, shared within the prefix
+		assert!(r.check(a));
+		assert!(r.check(b));
+		assert!(r.check(a));
+		assert!(!r.check(b));
+		assert!(r.check(c));
+		// a token per 100ms
+		tokio::time::advance(Duration::from_millis(150)).await;
+		assert!(r.check(a));
+		assert!(!r.check(a));
+
+		let v6: IpAddr = "2001:db8:0:ff::1".parse().unwrap();
+		assert_eq!(r.prefix(v6), "2001:db8:0:0::".parse::<IpAddr>().unwrap());
+		assert_eq!(
+			r.prefix("::ffff:192.0.2.1".parse().unwrap()),
+			"192.0.2.0".parse::<IpAddr>().unwrap()
+		);
+
+		// refilled ones are swept
+		tokio::time::advance(SWEEP_INTERVAL * 2).await;
+		assert!(r.check(c));
+		assert_eq!(r.buckets.borrow().len(), 1);
+
+		// bounded
+		for i in 0..MAX_BUCKETS as u32 + 1 {
+			r.check(IpAddr::from((i << 8).to_be_bytes()));
+		}
+		assert!(r.buckets.borrow().len() <= MAX_BUCKETS);
+	}
+}
@@ -1,11 +1,25 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
+use hickory_proto::{
+	op::{Edns, Message, MessageType, OpCode, Query},
+	rr::{Name, Record, RecordType},
+	xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, FirstAnswer, RetryDnsHandle},
+};
 use hickory_resolver::{
-	config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
-	TokioAsyncResolver,
+	config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+	error::ResolveError,
+	name_server::{GenericConnector, NameServerPool},
+	AsyncResolver,
 };
+use log::*;
 
-use crate::conf::UpstreamSec;
+use crate::{
+	bind::BindProvider,
+	conf::UpstreamSec,
+	dohc::Dohc,
+	ecs::{Ecs, Subnet},
+	pool::Pool,
+};
 
 fn default_port(protocol: Protocol) -> u16 {
 	match protocol {
@@ -14,22 +28,164 @@ fn default_port(protocol: Protocol) -> u16 {
 		Protocol::Tls => 853,
 		Protocol::Https => 443,
 		Protocol::H3 => 443,
+		Protocol::Quic => 853,
 		_ => panic!("unsupported protocol: {}", protocol),
 	}
 }
 
-pub fn from(conf: &UpstreamSec) -> TokioAsyncResolver {
-	let mut config = ResolverConfig::new();
+type HickoryResolver = AsyncResolver<GenericConnector<BindProvider>>;
+// what's under AsyncResolver, for messages with EDNS options
+type HickoryExchange = RetryDnsHandle<NameServerPool<GenericConnector<BindProvider>>>;
+
+pub enum Resolver {
+	Hickory(Box<HickoryResolver>),
+	Exchange(Box<HickoryExchange>),
+	Dohc(Dohc),
+	// one of the above per address, picked by strategy
+	Pool(Pool<Resolver>),
+}
+
+impl Resolver {
+	pub async fn lookup(&self, name: &str, rtype: RecordType) -> Result<Vec<Record>, ResolveError> {
+		Ok(self.lookup_ecs(name, rtype, None).await?.0)
+	}
+
+	// with the ECS option of the response, if any
+	pub async fn lookup_ecs(
+		&self,
+		name: &str,
+		rtype: RecordType,
+		ecs: Option<Subnet>,
+	) -> Result<(Vec<Record>, Option<Subnet>), ResolveError> {
+		match self {
+			// CAUTION: hickory warned this interface may change in the future
+			//	it's an Exchange when ECS is configured
+			Resolver::Hickory(r) => Ok((r.lookup(name, rtype).await?.records().to_vec(), None)),
+			Resolver::Exchange(h) => {
+				let msg = mk_query(Name::from_ascii(name)?, rtype, ecs);
+				exchange_lookup(h, msg).await
+			}
+			Resolver::Dohc(d) => {
+				dohc_lookup(d, mk_query(Name::from_ascii(name)?, rtype, ecs)).await
+			}
+			Resolver::Pool(p) => Box::pin(p.run(|r| r.lookup_ecs(name, rtype, ecs))).await,
+		}
+	}
+
+	pub async fn reverse_lookup(&self, ip: IpAddr) -> Result<Vec<Record>, ResolveError> {
+		let ptr = || mk_query(Name::from(ip), RecordType::PTR, None);
+		match self {
+			Resolver::Hickory(r) => Ok(r.reverse_lookup(ip).await?.as_lookup().records().to_vec()),
+			Resolver::Exchange(h) => Ok(exchange_lookup(h, ptr()).await?.0),
+			Resolver::Dohc(d) => Ok(dohc_lookup(d, ptr()).await?.0),
+			Resolver::Pool(p) => Box::pin(p.run(|r| r.reverse_lookup(ip))).await,
+		}
+	}
+}
+
+fn mk_query(name: Name, rtype: RecordType, ecs: Option<Subnet>) -> Message {
+	let mut query = Query::new();
+	query.set_name(name);
+	query.set_query_type(rtype);
+
+	let mut msg = Message::new();
+	// RFC 8484 4.1 recommends 0 for cache friendliness
+	//	hickory sets its own for UDP and TCP
+	msg.set_id(0);
+	msg.set_message_type(MessageType::Query);
+	msg.set_op_code(OpCode::Query);
+	msg.set_recursion_desired(true);
+	msg.add_query(query);
+	// same as hickory with edns0 enabled
+	let mut edns = Edns::new();
+	edns.set_max_payload(1232);
+	if let Some(ecs) = ecs {
+		edns.options_mut().insert(ecs.to_option());
+	}
+	msg.set_edns(edns);
+	msg
+}
+
+fn answers(resp: DnsResponse) -> Result<(Vec<Record>, Option<Subnet>), ResolveError> {
+	let ecs = Subnet::from_message(&resp);
+	// turn empty answers to NoRecordsFound, like hickory does
+	let resp = ResolveError::from_response(resp, false)?;
+	Ok((resp.answers().to_vec(), ecs))
+}
+
+async fn exchange_lookup(
+	h: &HickoryExchange,
+	msg: Message,
+) -> Result<(Vec<Record>, Option<Subnet>), ResolveError> {
+	let resp = h
+		.send(DnsRequest::new(msg, DnsRequestOptions::default()))
+		.first_answer()
+		.await?;
+	answers(resp)
+}
+
+async fn dohc_lookup(
+	dohc: &Dohc,
+	msg: Message,
+) -> Result<(Vec<Record>, Option<Subnet>), ResolveError> {
+	let resp = dohc
+		.exchange(msg.to_vec()?)
+		.await
+		.map_err(|e| ResolveError::from(format!("dohc: {}", e)))?;
+	answers(DnsResponse::from_message(Message::from_vec(&resp)?)?)
+}
+
+pub fn from(conf: &UpstreamSec) -> Resolver {
+	if let Some(strategy) = conf.strategy {
+		if conf.addrs.len() > 1 {
+			return Resolver::Pool(Pool::new(
+				strategy,
+				conf.addrs.iter().map(|a| {
+					let conf = UpstreamSec {
+						addrs: vec![*a],
+						strategy: None,
+						..conf.clone()
+					};
+					(*a, from(&conf))
+				}),
+			));
+		}
+	}
+
 	let port = conf.port.unwrap_or(default_port(conf.protocol));
 	let tls_dns_name = if conf.tls_dns_name.is_none()
 		&& (conf.protocol == Protocol::Tls
 			|| conf.protocol == Protocol::Https
-			|| conf.protocol == Protocol::H3)
+			|| conf.protocol == Protocol::H3
+			|| conf.protocol == Protocol::Quic)
 	{
-		Some(conf.addrs[0].to_string())
+		// the hostname, if addresses were resolved from one
+		match conf.hosts.first() {
+			Some(host) => Some(host.clone()),
+			None => Some(conf.addrs[0].to_string()),
+		}
 	} else {
 		conf.tls_dns_name.clone()
 	};
+
+	// hickory only does POST to /dns-query
+	if conf.dohc || (conf.protocol == Protocol::Https && !conf.dohc_opts.is_default_request()) {
+		return Resolver::Dohc(Dohc::new(
+			tls_dns_name.unwrap(),
+			&conf.addrs,
+			Some(port),
+			&conf.dohc_opts,
+			&conf.bind,
+		));
+	}
+	if conf.dohc_opts.proxy.is_some() {
+		warn!(
+			"upstream {}: proxy is only supported with protocol doh-reqwest, ignored",
+			conf.name
+		);
+	}
+
+	let mut config = ResolverConfig::new();
 	for addr in &conf.addrs {
 		config.add_name_server(NameServerConfig {
 			socket_addr: SocketAddr::new(*addr, port),
@@ -55,29 +211,47 @@ pub fn from(conf: &UpstreamSec) -> TokioAsyncResolver {
 	// default false
 	opts.edns0 = true;
 
-	TokioAsyncResolver::tokio(config, opts)
+	let provider = GenericConnector::new(BindProvider::new(conf.bind.clone()));
+	if conf.ecs != Ecs::Strip {
+		let attempts = opts.attempts;
+		let pool = NameServerPool::from_config(
+			NameServerConfigGroup::from(config.name_servers().to_vec()),
+			opts,
+			provider,
+		);
+		return Resolver::Exchange(Box::new(RetryDnsHandle::new(pool, attempts)));
+	}
+	Resolver::Hickory(Box::new(AsyncResolver::new(config, opts, provider)))
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use hickory_proto::rr::RecordType;
 
 	#[tokio::test]
 	async fn test() {
 		let r = from(&UpstreamSec {
-			name: "".to_string(),
 			protocol: Protocol::Https,
 			addrs: vec!["1.1.1.1".parse().unwrap()],
-			port: None,
 			tls_dns_name: Some("cloudflare-dns.com".to_string()),
-			ips: vec![],
-			domains: vec![],
-			disable_aaaa: false,
+			..UpstreamSec::new("")
 		});
 		let resp = r.lookup("www.example.com", RecordType::A).await.unwrap();
 		for a in resp {
 			println!("{:?}", a);
 		}
 	}
+
+	#[test]
+	#[should_panic(expected = "fwmark")]
+	fn test_dohc_fwmark() {
+		let mut conf = UpstreamSec {
+			protocol: Protocol::Https,
+			dohc: true,
+			addrs: vec!["1.1.1.1".parse().unwrap()],
+			..UpstreamSec::new("")
+		};
+		conf.bind.fwmark = Some(1);
+		from(&conf);
+	}
 }
@@ -0,0 +1,182 @@
+// DNS stamps, https://dnscrypt.info/stamps-specifications
+//	only plain DNS, DoH, DoT and DoQ, DNSCrypt and relays are not supported
+//	certificate hashes and bootstrap IPs are ignored
+
+use std::{net::IpAddr, str::FromStr};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hickory_resolver::config::Protocol;
+
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[derive(PartialEq)]
+pub struct Stamp {
+	pub protocol: Protocol,
+	pub addr: Option<IpAddr>,
+	pub port: Option<u16>,
+	pub hostname: Option<String>,
+	pub path: Option<String>,
+}
+
+impl FromStr for Stamp {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s
+			.strip_prefix("sdns://")
+			.ok_or_else(|| "expected sdns://".to_string())?;
+		let b = URL_SAFE_NO_PAD
+			.decode(s.trim_end_matches('='))
+			.map_err(|e| e.to_string())?;
+		let mut r = Reader(&b);
+
+		let protocol = match r.byte()? {
+			0x00 => Protocol::Udp,
+			0x02 => Protocol::Https,
+			0x03 => Protocol::Tls,
+			0x04 => Protocol::Quic,
+			p => return Err(format!("unsupported stamp protocol: 0x{:02x}", p)),
+		};
+		// props: DNSSEC, no log, no filter, we don't care
+		r.take(8)?;
+		let (addr, mut port) = parse_addr(r.lp()?)?;
+		if protocol == Protocol::Udp {
+			return Ok(Self {
+				protocol,
+				addr: Some(addr.ok_or_else(|| "address required".to_string())?),
+				port,
+				hostname: None,
+				path: None,
+			});
+		}
+
+		r.vlp()?;
+		let hostname = r.lp()?;
+		let hostname = match hostname.rsplit_once(':') {
+			Some((h, p)) => {
+				let p = p.parse().map_err(|_| format!("invalid port: {}", p))?;
+				port = port.or(Some(p));
+				h
+			}
+			None => hostname,
+		};
+		if hostname.is_empty() {
+			return Err("hostname required".to_string());
+		}
+		let path = if protocol == Protocol::Https {
+			Some(r.lp()?.to_string())
+		} else {
+			None
+		};
+		Ok(Self {
+			protocol,
+			addr,
+			port,
+			hostname: Some(hostname.to_string()),
+			path,
+		})
+	}
+}
+
+// "1.1.1.1", "1.1.1.1:53", "[2620:fe::fe]", "[2620:fe::fe]:53" or empty
+fn parse_addr(s: &str) -> Result<(Option<IpAddr>, Option<u16>), String> {
+	if s.is_empty() {
+		return Ok((None, None));
+	}
+	let (a, p) = match s.strip_prefix('[') {
+		Some(s) => match s.split_once(']') {
+			Some((a, "")) => (a, None),
+			Some((a, p)) => (a, Some(p.strip_prefix(':').unwrap_or(p))),
+			None => return Err(format!("invalid address: {}", s)),
+		},
+		None => match s.split_once(':') {
+			Some((a, p)) => (a, Some(p)),
+			None => (s, None),
+		},
+	};
+	let a = a.parse().map_err(|_| format!("invalid address: {}", a))?;
+	let p = match p {
+		Some(p) => Some(p.parse().map_err(|_| format!("invalid port: {}", p))?),
+		None => None,
+	};
+	Ok((Some(a), p))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+		if self.0.len() < n {
+			return Err("stamp truncated".to_string());
+		}
+		let (h, t) = self.0.split_at(n);
+		self.0 = t;
+		Ok(h)
+	}
+
+	fn byte(&mut self) -> Result<u8, String> {
+		Ok(self.take(1)?[0])
+	}
+
+	// length prefixed string
+	fn lp(&mut self) -> Result<&'a str, String> {
+		let n = self.byte()? as usize;
+		std::str::from_utf8(self.take(n)?).map_err(|e| e.to_string())
+	}
+
+	// variable length prefixed set, the high bit of length means more to follow
+	fn vlp(&mut self) -> Result<Vec<&'a [u8]>, String> {
+		let mut v = Vec::new();
+		loop {
+			let n = self.byte()?;
+			v.push(self.take((n & 0x7f) as usize)?);
+			if n & 0x80 == 0 {
+				return Ok(v);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test() {
+		let s: Stamp = "sdns://AgcAAAAAAAAABzEuMC4wLjGgENk8mGSlIfMGXMOlIlCcKvq7AVgcrZxtjon911-ep0cg63Ul-I8NlFj4GplQGb_TTLiczclX57DvMV8Q-JdjgRgSZG5zLmNsb3VkZmxhcmUuY29tCi9kbnMtcXVlcnk"
+			.parse()
+			.unwrap();
+		assert_eq!(
+			s,
+			Stamp {
+				protocol: Protocol::Https,
+				addr: Some("1.0.0.1".parse().unwrap()),
+				port: None,
+				hostname: Some("dns.cloudflare.com".to_string()),
+				path: Some("/dns-query".to_string()),
+			}
+		);
+
+		let s: Stamp = "sdns://AwEAAAAAAAAAElsyNjIwOmZlOjpmZV06ODg1MwANZG5zLnF1YWQ5Lm5ldA"
+			.parse()
+			.unwrap();
+		assert_eq!(s.protocol, Protocol::Tls);
+		assert_eq!(s.addr, Some("2620:fe::fe".parse().unwrap()));
+		assert_eq!(s.port, Some(8853));
+		assert_eq!(s.hostname.as_deref(), Some("dns.quad9.net"));
+
+		let s: Stamp = "sdns://BAEAAAAAAAAAAAATZG5zLmFkZ3VhcmQtZG5zLmNvbQ"
+			.parse()
+			.unwrap();
+		assert_eq!(s.protocol, Protocol::Quic);
+		assert_eq!(s.addr, None);
+		assert_eq!(s.hostname.as_deref(), Some("dns.adguard-dns.com"));
+
+		let s: Stamp = "sdns://AAEAAAAAAAAADDkuOS45Ljk6NTM1Mw".parse().unwrap();
+		assert_eq!(s.protocol, Protocol::Udp);
+		assert_eq!(s.addr, Some("9.9.9.9".parse().unwrap()));
+		assert_eq!(s.port, Some(5353));
+
+		assert!("sdns://AQ".parse::<Stamp>().is_err());
+		assert!("sdns://AgcAAAAA".parse::<Stamp>().is_err());
+	}
+}
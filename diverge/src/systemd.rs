@@ -0,0 +1,134 @@
+// systemd socket activation and sd_notify, without libsystemd
+//	https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+//	https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+
+use std::{
+	env,
+	ffi::OsStr,
+	os::{
+		fd::{AsRawFd, FromRawFd, RawFd},
+		unix::{ffi::OsStrExt, net::UnixDatagram},
+	},
+	path::Path,
+	sync::Mutex,
+	time::Duration,
+};
+
+use log::*;
+use socket2::{SockAddr, Socket, Type};
+
+const LISTEN_FDS_START: RawFd = 3;
+
+// passed by systemd, taken by listeners with a matching address
+static INHERITED: Mutex<Vec<Socket>> = Mutex::new(Vec::new());
+
+// read LISTEN_FDS, should be called once, early, before any threads are spawned
+pub fn init() {
+	let pid_ok = env::var("LISTEN_PID").is_ok_and(|p| p == std::process::id().to_string());
+	let n: RawFd = env::var("LISTEN_FDS")
+		.ok()
+		.and_then(|n| n.parse().ok())
+		.unwrap_or(0);
+	// so they are not inherited further
+	env::remove_var("LISTEN_PID");
+	env::remove_var("LISTEN_FDS");
+	env::remove_var("LISTEN_FDNAMES");
+	if !pid_ok || n <= 0 {
+		return;
+	}
+	let mut inherited = INHERITED.lock().unwrap();
+	for fd in LISTEN_FDS_START..LISTEN_FDS_START + n {
+		// SAFETY: systemd passes these to us, and no one else uses them
+		let s = unsafe { Socket::from_raw_fd(fd) };
+		if let Err(e) = s.set_cloexec(true).and_then(|_| s.set_nonblocking(true)) {
+			warn!("inherited fd {}: {}", fd, e);
+			continue;
+		}
+		inherited.push(s);
+	}
+	info!("{} sockets inherited from systemd", inherited.len());
+}
+
+// take an inherited socket of the type, bound to the address
+pub fn take(addr: &SockAddr, ty: Type) -> Option<Socket> {
+	take_by(|s| s.r#type().is_ok_and(|t| t == ty) && s.local_addr().is_ok_and(|a| a == *addr))
+}
+
+pub fn take_unix(path: &Path) -> Option<Socket> {
+	take_by(|s| {
+		s.r#type().is_ok_and(|t| t == Type::STREAM)
+			&& s.local_addr()
+				.is_ok_and(|a| a.as_pathname().is_some_and(|p| p == path))
+	})
+}
+
+fn take_by(f: impl Fn(&Socket) -> bool) -> Option<Socket> {
+	let mut inherited = INHERITED.lock().unwrap();
+	let i = inherited.iter().position(f)?;
+	debug!("using inherited socket {}", describe(&inherited[i]));
+	Some(inherited.swap_remove(i))
+}
+
+// complain about sockets no listener took, and close them
+pub fn check_unused() {
+	for s in INHERITED.lock().unwrap().drain(..) {
+		warn!(
+			"inherited socket {} doesn't match any listener",
+			describe(&s)
+		);
+	}
+}
+
+fn describe(s: &Socket) -> String {
+	match s.local_addr() {
+		Ok(a) => match (a.as_socket(), a.as_pathname()) {
+			(Some(a), _) => a.to_string(),
+			(_, Some(p)) => format!("unix:{}", p.display()),
+			_ => "(unnamed)".to_string(),
+		},
+		Err(e) => format!("fd {}: {}", s.as_raw_fd(), e),
+	}
+}
+
+pub fn notify(state: &str) {
+	let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+		return;
+	};
+	let r = UnixDatagram::unbound().and_then(|s| {
+		let path = path.as_encoded_bytes();
+		match path.strip_prefix(b"@") {
+			// abstract namespace
+			#[cfg(any(target_os = "linux", target_os = "android"))]
+			Some(name) => {
+				use std::os::linux::net::SocketAddrExt;
+				let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+				s.send_to_addr(state.as_bytes(), &addr)
+			}
+			_ => s.send_to(state.as_bytes(), OsStr::from_bytes(path)),
+		}
+	});
+	if let Err(e) = r {
+		warn!("sd_notify {} error: {}", state, e);
+	}
+}
+
+// half of WATCHDOG_USEC, if the watchdog is enabled for us
+pub fn watchdog_interval() -> Option<Duration> {
+	if env::var("WATCHDOG_PID").is_ok_and(|p| p != std::process::id().to_string()) {
+		return None;
+	}
+	let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	Some(Duration::from_micros(usec / 2))
+}
+
+pub async fn watchdog() {
+	let Some(intv) = watchdog_interval() else {
+		return;
+	};
+	info!("systemd watchdog every {:?}", intv);
+	let mut intv = tokio::time::interval(intv);
+	loop {
+		intv.tick().await;
+		notify("WATCHDOG=1");
+	}
+}
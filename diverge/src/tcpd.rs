@@ -1,55 +1,86 @@
-// this is deprecated, since support for DNS over TCP is so bad
+// DNS over TCP, and over TLS (RFC 7858) with a TLS acceptor
+//	plain TCP support is so bad in clients, DoT is what this is really for
 
-pub async fn tcpd(listen: SocketAddr, diverge: Diverge) -> Option<()> {
-	let diverge = Rc::new(diverge);
-	let quit = Rc::new(Cell::new(false));
+use std::{io::ErrorKind, net::SocketAddr, rc::Rc};
 
-	let s = match listen {
-		SocketAddr::V4(_) => TcpSocket::new_v4().unwrap(),
-		SocketAddr::V6(_) => TcpSocket::new_v6().unwrap(),
-	};
-	s.set_nodelay(true).unwrap();
-	s.bind(listen).unwrap();
+use log::*;
+use tokio::{
+	io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	select,
+	sync::mpsc,
+	task::{self, JoinSet},
+	time::{timeout, Duration},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{acl::Access, bind::listen_tcp, diverge::Diverge};
 
-	let d = s.listen(64).unwrap();
-	info!("listening on {}", d.local_addr().unwrap());
+// RFC 1035 4.2.2 recommends 120s
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const READ_TIMEOUT: Duration = Duration::from_secs(7);
+
+pub async fn tcpd(listen: SocketAddr, tls: Option<TlsAcceptor>, diverge: Rc<Diverge>) {
+	let d = match listen_tcp(listen) {
+		Ok(d) => d,
+		Err(e) => {
+			error!("tcp bind {} error: {}", listen, e);
+			return;
+		}
+	};
+	match tls {
+		Some(_) => info!("listening on TLS {}", d.local_addr().unwrap()),
+		None => info!("listening on TCP {}", d.local_addr().unwrap()),
+	}
 
+	// connections are aborted along with this task
+	let mut conns = JoinSet::new();
 	loop {
-		select! {
-			d = d.accept() => {
-				let (socket, addr) = d.map_err(|e| error!("tcp accept error: {}", e)).ok()?;
-				debug!("new connection from {}", addr);
-				let _ = task::spawn_local(handle_conn(
-					diverge.clone(),
-					socket,
-					quit.clone(),
-					// RFC 1035 4.2.2 recommends 120s
-					Duration::from_secs(120),
-					Duration::from_secs(7)
-				)).await;
+		let r = select! {
+			_ = diverge.stopping() => break,
+			r = d.accept() => r,
+		};
+		let (socket, addr) = match r {
+			Ok(a) => a,
+			Err(e) => {
+				error!("tcp accept error: {}", e);
+				continue;
 			}
-			_ = ctrl_c() => {
-				info!("ctrl-c received, exiting");
-				break;
+		};
+		debug!("new connection from {}", addr);
+		let _ = socket.set_nodelay(true);
+		let diverge = diverge.clone();
+		let tls = tls.clone();
+		conns.spawn_local(async move {
+			match tls {
+				Some(tls) => match timeout(READ_TIMEOUT, tls.accept(socket)).await {
+					Ok(Ok(s)) => handle_conn(diverge, s, addr, IDLE_TIMEOUT, READ_TIMEOUT).await,
+					Ok(Err(e)) => debug!("tls handshake error from {}: {}", addr, e),
+					Err(_) => debug!("tls handshake timeout from {}", addr),
+				},
+				None => handle_conn(diverge, socket, addr, IDLE_TIMEOUT, READ_TIMEOUT).await,
 			}
-		}
+		});
+		while conns.try_join_next().is_some() {}
 	}
-	quit.set(true);
-	Some(())
+	// connections stop reading on shutdown too, and answer what's in flight
+	drop(d);
+	while conns.join_next().await.is_some() {}
 }
 
-async fn handle_conn(
+async fn handle_conn<S>(
 	diverge: Rc<Diverge>,
-	s: TcpStream,
-	quit: Rc<Cell<bool>>,
+	s: S,
+	client: SocketAddr,
 	d_timeout: Duration,
 	r_timeout: Duration,
-) -> Option<()> {
-	let (mut r, mut w) = s.into_split();
+) where
+	S: AsyncRead + AsyncWrite + 'static,
+{
+	let (mut r, mut w) = split(s);
 
 	// spawn a task to handle writing with a channel
 	let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1);
-	task::spawn_local(async move {
+	let writer = task::spawn_local(async move {
 		// RFC 7766 8 says we SHOULD pass them in a single write
 		let mut buf = Vec::with_capacity(0x1000);
 		while let Some(msg) = rx.recv().await {
@@ -62,20 +93,29 @@ async fn handle_conn(
 			buf.extend_from_slice(&msg);
 			match w.write_all(&buf).await {
 				Ok(_) => trace!("client write task wrote {} bytes", buf.len()),
-				Err(e) => trace!("client write task write error: {}", e),
+				Err(e) => {
+					trace!("client write task write error: {}", e);
+					break;
+				}
 			}
 		}
+		// close_notify for TLS
+		let _ = w.shutdown().await;
 		trace!("client write task ended")
 	});
 
 	// read client requests
 	let mut buf = vec![0u8; 0x1000];
+	let mut queries = JoinSet::new();
 	loop {
-		if quit.get() {
-			debug!("tcp handle task quit");
-			break;
-		}
-		let len = match timeout(d_timeout, r.read_u16()).await {
+		let read = select! {
+			_ = diverge.stopping() => {
+				debug!("shutting down, tcp connection closed");
+				break;
+			}
+			read = timeout(d_timeout, r.read_u16()) => read,
+		};
+		let len = match read {
 			Ok(Ok(len)) => len,
 			Err(_) => {
 				info!("tcp timeout while waiting client request, connection closed");
@@ -87,7 +127,7 @@ async fn handle_conn(
 			}
 			Ok(Err(e)) => {
 				warn!("tcp error while waiting client request: {}", e);
-				return None;
+				break;
 			}
 		};
 		if buf.len() < len as usize {
@@ -100,15 +140,26 @@ async fn handle_conn(
 			}
 			Ok(Err(e)) => {
 				debug!("tcp error while reading dns request: {}", e);
+				break;
 			}
 			Ok(Ok(_)) => {}
 		}
+		match diverge.admit(client.ip(), false) {
+			Access::Allow => {}
+			Access::Drop => break,
+			access => {
+				if let Some(a) = diverge.deny(&buf[0..len as usize], access) {
+					let _ = tx.send(a).await;
+				}
+				continue;
+			}
+		}
 		// RFC 7766 6.2.1.1 pipelining
 		let diverge = diverge.clone();
 		let tx = tx.clone();
 		let buf = buf[0..len as usize].to_vec();
-		task::spawn_local(async move {
-			if let Some(a) = diverge.query(buf).await {
+		queries.spawn_local(async move {
+			if let Some(a) = diverge.query(buf, client).await {
 				if tx.send(a).await.is_err() {
 					debug!("channel write error");
 				}
@@ -116,6 +167,26 @@ async fn handle_conn(
 				debug!("diverge error");
 			}
 		});
+		while queries.try_join_next().is_some() {}
+	}
+	// answer what's in flight, then the writer ends with the last sender
+	drop(tx);
+	while queries.join_next().await.is_some() {}
+	let _ = writer.await;
+}
+
+fn align_to(n: usize, align: usize) -> usize {
+	n.div_ceil(align) * align
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_align_to() {
+		assert_eq!(align_to(1, 0x1000), 0x1000);
+		assert_eq!(align_to(0x1000, 0x1000), 0x1000);
+		assert_eq!(align_to(0x1001, 0x1000), 0x2000);
 	}
-	Some(())
 }
@@ -0,0 +1,30 @@
+// TLS for the DoH and DoT listeners
+
+use std::{path::Path, sync::Arc};
+
+use tokio_rustls::{
+	rustls::{
+		crypto::ring,
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+		ServerConfig,
+	},
+	TlsAcceptor,
+};
+
+// PEM files, the cert file can have the chain in it
+pub fn acceptor(cert: impl AsRef<Path>, key: impl AsRef<Path>, alpn: &[&[u8]]) -> TlsAcceptor {
+	let (cert, key) = (cert.as_ref(), key.as_ref());
+	let certs = CertificateDer::pem_file_iter(cert)
+		.and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+		.unwrap_or_else(|e| panic!("failed to load cert {}: {}", cert.display(), e));
+	let key = PrivateKeyDer::from_pem_file(key)
+		.unwrap_or_else(|e| panic!("failed to load key {}: {}", key.display(), e));
+	let mut c = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.unwrap_or_else(|e| panic!("invalid cert or key: {}", e));
+	c.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+	TlsAcceptor::from(Arc::new(c))
+}
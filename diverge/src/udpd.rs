@@ -1,46 +1,278 @@
-use std::{net::SocketAddr, rc::Rc};
+// DNS over UDP
+//	receives and sends in batches with recvmmsg/sendmmsg on Linux
+
+use std::{io, net::SocketAddr, rc::Rc};
 
 use log::*;
-use tokio::{net::UdpSocket, select, signal::ctrl_c, task};
+use tokio::{
+	net::UdpSocket,
+	select,
+	sync::mpsc,
+	task::{self, JoinSet},
+};
 
-use crate::diverge::Diverge;
+use crate::{acl::Access, bind::listen_udp, diverge::Diverge};
 
-pub async fn udpd(listen: SocketAddr, diverge: Diverge) {
-	let diverge = Rc::new(diverge);
+// EDNS allows up to this, don't truncate anything
+const MAX_DATAGRAM: usize = 0xffff;
+// datagrams per recvmmsg/sendmmsg
+const BATCH: usize = 32;
+// queries in flight, beyond this datagrams wait in the socket buffer
+//	or get dropped by the kernel, instead of piling up here
+const MAX_INFLIGHT: usize = 1024;
 
-	let s = Rc::new(UdpSocket::bind(listen).await.unwrap());
+pub async fn udpd(listen: SocketAddr, diverge: Rc<Diverge>) {
+	let s = match listen_udp(listen) {
+		Ok(s) => Rc::new(s),
+		Err(e) => {
+			error!("udp bind {} error: {}", listen, e);
+			return;
+		}
+	};
 	info!("listening on UDP {}", s.local_addr().unwrap());
 
-	let mut buf = vec![0u8; 0x600];
+	// answers are sent by a single task, batched
+	let (tx, rx) = mpsc::channel(BATCH);
+	let sender = task::spawn_local(send_loop(s.clone(), rx));
+
+	let mut bufs = vec![vec![0u8; MAX_DATAGRAM]; batch_size()];
+	let mut from = Vec::with_capacity(bufs.len());
+	let mut queries = JoinSet::new();
 	loop {
-		select! {
-			r = s.recv_from(&mut buf) => {
-				match r {
-					Ok((len, addr)) => {
-						trace!("udp recv {} bytes from {}", len, addr);
-						let diverge = diverge.clone();
-						let w = s.clone();
-						let buf = buf[0..len].to_vec();
-						task::spawn_local(async move {
-							if let Some(a) = diverge.query(buf).await {
-								if let Err(e) = w.send_to(&a, addr).await {
-									error!("udp send error: {}", e);
-								}
-							} else {
-								error!("diverge error");
-							}
-						});
-					}
-					Err(e) => {
-						error!("udp recv error: {}", e);
-						break;
+		from.clear();
+		let r = select! {
+			_ = diverge.stopping() => break,
+			r = recv_batch(&s, &mut bufs, &mut from) => r,
+		};
+		if let Err(e) = r {
+			error!("udp recv error: {}", e);
+			return;
+		}
+		for (buf, &(len, addr)) in bufs.iter().zip(from.iter()) {
+			trace!("udp recv {} bytes from {}", len, addr);
+			match diverge.admit(addr.ip(), true) {
+				Access::Allow => {}
+				Access::Drop => continue,
+				access => {
+					// answered right here, no task for these
+					if let Some(a) = diverge.deny(&buf[0..len], access) {
+						let _ = tx.try_send((a, addr));
 					}
+					continue;
 				}
 			}
-			_ = ctrl_c() => {
-				info!("ctrl-c received, exiting");
-				break;
+			while queries.len() >= MAX_INFLIGHT {
+				queries.join_next().await;
 			}
+			let diverge = diverge.clone();
+			let tx = tx.clone();
+			let q = buf[0..len].to_vec();
+			queries.spawn_local(async move {
+				if let Some(a) = diverge.query(q, addr).await {
+					let _ = tx.send((a, addr)).await;
+				} else {
+					error!("diverge error");
+				}
+			});
+		}
+		while queries.try_join_next().is_some() {}
+	}
+	// answer what's in flight
+	debug!("udp {}: {} queries in flight", listen, queries.len());
+	while queries.join_next().await.is_some() {}
+	drop(tx);
+	let _ = sender.await;
+}
+
+async fn send_loop(s: Rc<UdpSocket>, mut rx: mpsc::Receiver<(Vec<u8>, SocketAddr)>) {
+	let mut batch = Vec::with_capacity(batch_size());
+	while rx.recv_many(&mut batch, batch_size()).await > 0 {
+		let mut pending = &batch[..];
+		while !pending.is_empty() {
+			match send_batch(&s, pending).await {
+				Ok(n) => pending = &pending[n..],
+				Err(e) => {
+					// skip the offending one
+					error!("udp send to {} error: {}", pending[0].1, e);
+					pending = &pending[1..];
+				}
+			}
+		}
+		batch.clear();
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn batch_size() -> usize {
+	BATCH
+}
+
+#[cfg(not(target_os = "linux"))]
+fn batch_size() -> usize {
+	1
+}
+
+// fills from with (len, addr) for bufs received
+#[cfg(target_os = "linux")]
+async fn recv_batch(
+	s: &UdpSocket,
+	bufs: &mut [Vec<u8>],
+	from: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<()> {
+	use std::os::fd::AsRawFd;
+	use tokio::io::Interest;
+	s.async_io(Interest::READABLE, || mmsg::recv(s.as_raw_fd(), bufs, from))
+		.await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_batch(
+	s: &UdpSocket,
+	bufs: &mut [Vec<u8>],
+	from: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<()> {
+	from.push(s.recv_from(&mut bufs[0]).await?);
+	Ok(())
+}
+
+// returns how many were sent
+#[cfg(target_os = "linux")]
+async fn send_batch(s: &UdpSocket, batch: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+	use std::os::fd::AsRawFd;
+	use tokio::io::Interest;
+	s.async_io(Interest::WRITABLE, || mmsg::send(s.as_raw_fd(), batch))
+		.await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_batch(s: &UdpSocket, batch: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+	let (a, addr) = &batch[0];
+	s.send_to(a, *addr).await?;
+	Ok(1)
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+	use std::{
+		io,
+		mem::{size_of, zeroed},
+		net::SocketAddr,
+		os::fd::RawFd,
+		ptr,
+	};
+
+	use socket2::SockAddr;
+
+	pub fn recv(
+		fd: RawFd,
+		bufs: &mut [Vec<u8>],
+		from: &mut Vec<(usize, SocketAddr)>,
+	) -> io::Result<()> {
+		let n = bufs.len();
+		let mut iovs: Vec<_> = bufs
+			.iter_mut()
+			.map(|b| libc::iovec {
+				iov_base: b.as_mut_ptr().cast(),
+				iov_len: b.len(),
+			})
+			.collect();
+		// SAFETY: all zeroes is valid for these C structs
+		let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; n];
+		let mut msgs: Vec<libc::mmsghdr> = iovs
+			.iter_mut()
+			.zip(addrs.iter_mut())
+			.map(|(iov, addr)| {
+				let mut m: libc::mmsghdr = unsafe { zeroed() };
+				m.msg_hdr.msg_name = ptr::from_mut(addr).cast();
+				m.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+				m.msg_hdr.msg_iov = iov;
+				m.msg_hdr.msg_iovlen = 1;
+				m
+			})
+			.collect();
+		// SAFETY: msgs point to iovs and addrs, which outlive the call
+		let r = unsafe {
+			libc::recvmmsg(
+				fd,
+				msgs.as_mut_ptr(),
+				n as _,
+				libc::MSG_DONTWAIT,
+				ptr::null_mut(),
+			)
+		};
+		if r < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		for (m, addr) in msgs.iter().zip(addrs).take(r as usize) {
+			// SAFETY: filled by the kernel, with the length it reported
+			let addr = unsafe { SockAddr::new(addr, m.msg_hdr.msg_namelen) };
+			// not possible on an IP socket
+			let Some(addr) = addr.as_socket() else {
+				continue;
+			};
+			from.push((m.msg_len as usize, addr));
+		}
+		Ok(())
+	}
+
+	pub fn send(fd: RawFd, batch: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+		let addrs: Vec<SockAddr> = batch.iter().map(|(_, a)| (*a).into()).collect();
+		let mut iovs: Vec<_> = batch
+			.iter()
+			.map(|(b, _)| libc::iovec {
+				iov_base: b.as_ptr() as *mut _,
+				iov_len: b.len(),
+			})
+			.collect();
+		let mut msgs: Vec<libc::mmsghdr> = iovs
+			.iter_mut()
+			.zip(addrs.iter())
+			.map(|(iov, addr)| {
+				// SAFETY: all zeroes is valid for this C struct
+				let mut m: libc::mmsghdr = unsafe { zeroed() };
+				m.msg_hdr.msg_name = addr.as_ptr() as *mut _;
+				m.msg_hdr.msg_namelen = addr.len();
+				m.msg_hdr.msg_iov = iov;
+				m.msg_hdr.msg_iovlen = 1;
+				m
+			})
+			.collect();
+		// SAFETY: msgs point to iovs, addrs and batch, which outlive the call
+		//	the kernel doesn't write to the buffers
+		let r =
+			unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, libc::MSG_DONTWAIT) };
+		if r < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(r as usize)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_batch() {
+		let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let to = b.local_addr().unwrap();
+		// larger than the MTU
+		let batch = [(vec![1], to), (vec![0x5a; 4000], to), (vec![3; 3], to)];
+		let mut sent = 0;
+		while sent < batch.len() {
+			sent += send_batch(&a, &batch[sent..]).await.unwrap();
+		}
+
+		let mut bufs = vec![vec![0u8; MAX_DATAGRAM]; batch.len()];
+		let mut from = Vec::new();
+		while from.len() < batch.len() {
+			let n = from.len();
+			recv_batch(&b, &mut bufs[n..], &mut from).await.unwrap();
+		}
+		for (i, (len, addr)) in from.into_iter().enumerate() {
+			assert_eq!(addr, a.local_addr().unwrap());
+			assert_eq!(&bufs[i][0..len], &batch[i].0[..]);
 		}
 	}
 }
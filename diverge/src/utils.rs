@@ -4,6 +4,7 @@ use std::{
 	path::Path,
 };
 
+use hickory_proto::op::ResponseCode;
 use log::*;
 
 pub trait FromLst<T: Copy> {
@@ -44,3 +45,16 @@ pub fn read_lines(f: impl AsRef<Path>) -> Option<impl Iterator<Item = impl AsRef
 		Ok(f) => Some(BufReader::new(f).lines().map_while(Result::ok)),
 	}
 }
+
+// the mnemonic, instead of hickory's description
+pub fn rcode_str(rcode: ResponseCode) -> String {
+	match rcode {
+		ResponseCode::NoError => "NOERROR".to_string(),
+		ResponseCode::FormErr => "FORMERR".to_string(),
+		ResponseCode::ServFail => "SERVFAIL".to_string(),
+		ResponseCode::NXDomain => "NXDOMAIN".to_string(),
+		ResponseCode::NotImp => "NOTIMP".to_string(),
+		ResponseCode::Refused => "REFUSED".to_string(),
+		_ => u16::from(rcode).to_string(),
+	}
+}